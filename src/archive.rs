@@ -0,0 +1,134 @@
+use core::marker::PhantomData;
+
+use fid::FID;
+use rkyv::{Archive, Deserialize, Serialize};
+
+use crate::backend::rank1_in_block;
+use crate::words::{ArchivedWordBits, WordBits};
+use crate::WaveletMatrix;
+
+impl FID for ArchivedWordBits {
+    fn len(&self) -> u64 {
+        self.len.to_native()
+    }
+
+    fn rank1(&self, i: u64) -> u64 {
+        let block_words = self.block_words.to_native() as usize;
+        let block = i as usize / 64 / block_words;
+        let ones = self
+            .blocks
+            .get(block)
+            .map_or(self.ones.to_native(), |b| b.to_native());
+        rank1_in_block(block_words, ones, |j| self.words[j].to_native(), i)
+    }
+
+    fn get(&self, i: u64) -> bool {
+        (self.words[i as usize / 64].to_native() >> (i % 64)) & 1 > 0
+    }
+}
+
+impl FID for &ArchivedWordBits {
+    fn len(&self) -> u64 {
+        (**self).len()
+    }
+
+    fn rank1(&self, i: u64) -> u64 {
+        (**self).rank1(i)
+    }
+
+    fn get(&self, i: u64) -> bool {
+        (**self).get(i)
+    }
+}
+
+/// A `WaveletMatrix<T, WordBits>` in a form that can be archived with `rkyv`.
+///
+/// Build the matrix with `new_with_backend`, convert it with `into`, and
+/// archive it with `rkyv::to_bytes`. The archived bytes are queried in place
+/// through `ArchivedWaveletMatrix::view`.
+///
+/// `WaveletMatrix` itself cannot derive `Archive` because its default row
+/// type, `fid::BitVector`, is not archivable.
+#[derive(Archive, Serialize, Deserialize)]
+#[rkyv(archived = ArchivedWaveletMatrix)]
+pub struct ArchivableMatrix<T> {
+    rows: Vec<WordBits>,
+    size: u64,
+    len: u64,
+    partitions: Vec<u64>,
+    _t: PhantomData<T>,
+}
+
+impl<T> From<WaveletMatrix<T, WordBits>> for ArchivableMatrix<T> {
+    fn from(wm: WaveletMatrix<T, WordBits>) -> Self {
+        ArchivableMatrix {
+            rows: wm.rows,
+            size: wm.size,
+            len: wm.len,
+            partitions: wm.partitions,
+            _t: PhantomData,
+        }
+    }
+}
+
+impl<T> From<ArchivableMatrix<T>> for WaveletMatrix<T, WordBits> {
+    fn from(m: ArchivableMatrix<T>) -> Self {
+        WaveletMatrix {
+            rows: m.rows,
+            size: m.size,
+            len: m.len,
+            partitions: m.partitions,
+            _t: PhantomData,
+        }
+    }
+}
+
+impl<T> ArchivedWaveletMatrix<T> {
+    /// Returns a matrix answering queries directly over the archived rows.
+    ///
+    /// Only the partitions and one reference per row are copied.
+    pub fn view(&self) -> WaveletMatrix<T, &ArchivedWordBits> {
+        WaveletMatrix {
+            rows: self.rows.iter().collect(),
+            size: self.size.to_native(),
+            len: self.len.to_native(),
+            partitions: self.partitions.iter().map(|p| p.to_native()).collect(),
+            _t: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rkyv::rancor::Error;
+
+    #[test]
+    fn archived_round_trip() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::<u8, WordBits>::new_with_backend(numbers, 3);
+        let bytes = rkyv::to_bytes::<Error>(&ArchivableMatrix::from(wm.clone())).unwrap();
+        let archived = rkyv::access::<ArchivedWaveletMatrix<u8>, Error>(&bytes).unwrap();
+        let view = archived.view();
+        assert_eq!(view.len(), 12);
+        assert_eq!(view.to_vec(), numbers.to_vec());
+        for c in 0..8u8 {
+            for k in 0..=numbers.len() as u64 {
+                assert!(
+                    view.rank(c, k) == wm.rank(c, k),
+                    "view.rank({}, {}) == {}",
+                    c,
+                    k,
+                    wm.rank(c, k)
+                );
+            }
+            for k in 0..wm.rank(c, wm.len()) {
+                assert_eq!(view.select(c, k), wm.select(c, k));
+            }
+        }
+        assert_eq!(view.quantile(2..9, 3), wm.quantile(2..9, 3));
+
+        let back: ArchivableMatrix<u8> = rkyv::deserialize::<_, Error>(archived).unwrap();
+        assert!(WaveletMatrix::from(back) == wm);
+    }
+}
@@ -0,0 +1,133 @@
+use alloc::sync::Arc;
+
+use fid::{BitVector, FID};
+
+use crate::words::BuildOptions;
+
+/// A bit vector that can be built by appending bits one at a time.
+///
+/// Together with `FID` and `Default`, this is what a type needs to be used as
+/// the rows of a `WaveletMatrix`.
+pub trait PushBit: FID {
+    /// Appends a bit at the end of the vector.
+    fn push_bit(&mut self, b: bool);
+}
+
+/// A bit vector whose index layout can be chosen with `BuildOptions`.
+///
+/// This is what a row type needs to be built by
+/// `WaveletMatrix::new_with_size_and_opts`. Only types that apply the
+/// options implement it: `fid::BitVector` has a fixed index layout, so it
+/// does not.
+pub trait FromOptions: PushBit {
+    /// Creates an empty vector indexed as described by `opts`.
+    fn from_options(opts: BuildOptions) -> Self;
+}
+
+/// A structure that can report the number of bytes it occupies.
+pub trait HeapSize {
+    /// Returns the total size in bytes.
+    fn heap_size(&self) -> usize;
+
+    /// Returns whether `self` and `other` are views of the same allocation,
+    /// which `WaveletMatrix::heap_size` then counts once. Defaults to `false`.
+    fn shares_allocation(&self, _other: &Self) -> bool
+    where
+        Self: Sized,
+    {
+        false
+    }
+}
+
+// Default number of 64-bit words covered by one entry of a rank directory.
+pub(crate) const BLOCK_WORDS: usize = 8;
+
+// Counts the ones before bit `i` of a row stored as little-endian-bit words,
+// given `block_ones`, the number of ones before the block of `block_words`
+// words containing `i`.
+pub(crate) fn rank1_in_block<F: Fn(usize) -> u64>(
+    block_words: usize,
+    block_ones: u64,
+    word: F,
+    i: u64,
+) -> u64 {
+    let i = i as usize;
+    let w = i / 64;
+    let mut ones = block_ones;
+    for j in (w / block_words) * block_words..w {
+        ones += u64::from(word(j).count_ones());
+    }
+    if i % 64 != 0 {
+        let mask = (1u64 << (i % 64)) - 1;
+        ones += u64::from((word(w) & mask).count_ones());
+    }
+    ones
+}
+
+impl PushBit for BitVector {
+    fn push_bit(&mut self, b: bool) {
+        self.push(b);
+    }
+}
+
+impl HeapSize for BitVector {
+    fn heap_size(&self) -> usize {
+        self.size()
+    }
+}
+
+/// A row that may share its allocation with other rows, as built by
+/// `WaveletMatrix::new_dedup`.
+pub struct Shared<B>(pub(crate) Arc<B>);
+
+impl<B> Clone for Shared<B> {
+    fn clone(&self) -> Self {
+        Shared(self.0.clone())
+    }
+}
+
+impl<B: FID> FID for Shared<B> {
+    fn len(&self) -> u64 {
+        self.0.len()
+    }
+
+    fn rank(&self, b: bool, i: u64) -> u64 {
+        self.0.rank(b, i)
+    }
+
+    fn rank0(&self, i: u64) -> u64 {
+        self.0.rank0(i)
+    }
+
+    fn rank1(&self, i: u64) -> u64 {
+        self.0.rank1(i)
+    }
+
+    fn select(&self, b: bool, r: u64) -> u64 {
+        self.0.select(b, r)
+    }
+
+    fn select0(&self, r: u64) -> u64 {
+        self.0.select0(r)
+    }
+
+    fn select1(&self, r: u64) -> u64 {
+        self.0.select1(r)
+    }
+
+    fn get(&self, i: u64) -> bool {
+        self.0.get(i)
+    }
+}
+
+/// Reports the full size of the shared row. Rows sharing it are recognized
+/// with `shares_allocation`, so a matrix counts it once.
+impl<B: HeapSize> HeapSize for Shared<B> {
+    fn heap_size(&self) -> usize {
+        self.0.heap_size()
+    }
+
+    fn shares_allocation(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
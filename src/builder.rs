@@ -0,0 +1,70 @@
+use alloc::vec::Vec;
+use core::ops::{BitOr, Shl};
+
+use num_traits::Num;
+
+use crate::WaveletMatrix;
+
+/// Collects values one at a time and builds a `WaveletMatrix` from them.
+///
+/// The wavelet matrix layout depends on every value, so the values are
+/// buffered and the matrix is built in `build`.
+pub struct Builder<T> {
+    size: u64,
+    text: Vec<T>,
+}
+
+impl<T> Builder<T>
+where
+    T: Into<u64> + Copy + Clone + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
+{
+    /// Creates an empty builder for `size`-bit values.
+    pub fn new(size: u64) -> Self {
+        Builder {
+            size,
+            text: Vec::new(),
+        }
+    }
+
+    /// Creates a builder for `size`-bit values with room for `len` of them.
+    ///
+    /// Only the buffer of values is reserved. `fid::BitVector` cannot reserve
+    /// bits ahead of time, and the rows and partitions, one per bit of `size`,
+    /// are allocated at their final capacity when the matrix is built.
+    pub fn with_capacity(len: u64, size: u64) -> Self {
+        Builder {
+            size,
+            text: Vec::with_capacity(len as usize),
+        }
+    }
+
+    /// Appends `value` to the end of the text.
+    pub fn push(&mut self, value: T) {
+        self.text.push(value);
+    }
+
+    /// Returns the number of values pushed so far.
+    pub fn len(&self) -> u64 {
+        self.text.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// Builds the matrix from the pushed values.
+    pub fn build(self) -> WaveletMatrix<T> {
+        WaveletMatrix::new_with_size(self.text, self.size)
+    }
+
+    /// Alias of `build`.
+    pub fn finish(self) -> WaveletMatrix<T> {
+        self.build()
+    }
+}
+
+impl<T> Extend<T> for Builder<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        self.text.extend(iter);
+    }
+}
@@ -0,0 +1,62 @@
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error::Error;
+#[cfg(feature = "std")]
+use std::io;
+
+/// Errors returned by the checked operations of this crate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WaveletError {
+    /// An index is not less than the length it refers to.
+    IndexOutOfBounds { index: u64, len: u64 },
+    /// A value has bits set at or above the bit width of the matrix.
+    SymbolTooLarge { value: u64, size: u64 },
+    /// A bit width is greater than 64.
+    SizeTooLarge(u64),
+    /// A byte of a DNA sequence is not one of `A`, `C`, `G` or `T`.
+    InvalidNucleotide { byte: u8, position: u64 },
+    /// A serialized matrix ended before all of its data was read.
+    Truncated,
+    /// A serialized matrix is malformed.
+    InvalidFormat(&'static str),
+}
+
+impl fmt::Display for WaveletError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WaveletError::IndexOutOfBounds { index, len } => {
+                write!(f, "index {} is out of bounds for length {}", index, len)
+            }
+            WaveletError::SymbolTooLarge { value, size } => {
+                write!(f, "value {} does not fit in {} bits", value, size)
+            }
+            WaveletError::SizeTooLarge(size) => {
+                write!(f, "bit width {} is greater than 64", size)
+            }
+            WaveletError::InvalidNucleotide { byte, position } => write!(
+                f,
+                "invalid nucleotide {:?} at position {}",
+                char::from(*byte),
+                position
+            ),
+            WaveletError::Truncated => write!(f, "unexpected end of serialized data"),
+            WaveletError::InvalidFormat(reason) => write!(f, "invalid format: {}", reason),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for WaveletError {}
+
+/// Wraps the error so that it can be recovered with `io::Error::get_ref`.
+#[cfg(feature = "std")]
+impl From<WaveletError> for io::Error {
+    fn from(err: WaveletError) -> io::Error {
+        let kind = match err {
+            WaveletError::Truncated => io::ErrorKind::UnexpectedEof,
+            WaveletError::InvalidFormat(_) => io::ErrorKind::InvalidData,
+            _ => io::ErrorKind::InvalidInput,
+        };
+        io::Error::new(kind, err)
+    }
+}
@@ -0,0 +1,148 @@
+use core::cmp;
+use core::convert::TryInto;
+use core::marker::PhantomData;
+use core::ops::{BitOr, Shl};
+
+use fid::{BitVector, FID};
+use num_traits::Num;
+
+use crate::{fits_in, WaveletMatrix};
+
+/// A wavelet matrix with a bit width `W` fixed at compile time.
+///
+/// The rows are kept in an array rather than a `Vec`, so the number of levels
+/// is a constant and the descent loops can be unrolled. Queries behave like
+/// those of a `WaveletMatrix` built with `new_with_size(text, W)`.
+pub struct WaveletMatrixN<T, const W: usize> {
+    rows: [BitVector; W],
+    partitions: [u64; W],
+    len: u64,
+    _t: PhantomData<T>,
+}
+
+impl<T, const W: usize> WaveletMatrixN<T, W>
+where
+    T: Into<u64> + Copy + Clone + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
+{
+    /// Builds a matrix with `W` bits per symbol. Panics if `W` is greater than 64.
+    pub fn new<K: AsRef<[T]>>(text: K) -> Self {
+        let wm = WaveletMatrix::new_with_size(text, W as u64);
+        let partitions = wm.partitions[..].try_into().unwrap();
+        let rows = match wm.rows.try_into() {
+            Ok(rows) => rows,
+            Err(_) => unreachable!(),
+        };
+        WaveletMatrixN {
+            rows,
+            partitions,
+            len: wm.len,
+            _t: PhantomData,
+        }
+    }
+
+    /// Returns the value at position `k`.
+    ///
+    /// `k` must be less than `len()`.
+    pub fn access(&self, k: u64) -> T {
+        let mut i = k;
+        let mut n = T::zero();
+        for r in 0..W {
+            let bv = &self.rows[r];
+            if bv.get(i) {
+                i = self.partitions[r] + bv.rank1(i);
+                n = n | (T::one() << (W - r - 1) as u64);
+            } else {
+                i = bv.rank0(i);
+            }
+        }
+        n
+    }
+
+    /// Counts the occurrences of `c` in `text[0..k)`.
+    pub fn rank(&self, c: T, k: u64) -> u64 {
+        let n = c.into();
+        if !fits_in(n, W as u64) {
+            return 0;
+        }
+        let mut s = 0;
+        let mut e = cmp::min(k, self.len);
+        for r in 0..W {
+            let bv = &self.rows[r];
+            let b = (n >> (W - r - 1)) & 1 > 0;
+            s = bv.rank(b, s);
+            e = bv.rank(b, e);
+            if b {
+                s += self.partitions[r];
+                e += self.partitions[r];
+            }
+        }
+        e - s
+    }
+
+    /// Returns the position of the (k+1)-th occurrence of `c`.
+    ///
+    /// The occurrence must exist, i.e. `k < rank(c, len())`.
+    pub fn select(&self, c: T, k: u64) -> u64 {
+        let n = c.into();
+        let mut s = 0;
+        for r in 0..W {
+            let b = (n >> (W - r - 1)) & 1 > 0;
+            s = self.rows[r].rank(b, s);
+            if b {
+                s += self.partitions[r];
+            }
+        }
+        let mut e = s + k;
+        for r in (0..W).rev() {
+            let bv = &self.rows[r];
+            if (n >> (W - r - 1)) & 1 > 0 {
+                e = bv.select1(e - self.partitions[r]);
+            } else {
+                e = bv.select0(e);
+            }
+        }
+        e
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn same_answers_as_dynamic() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..5000).map(|_| rng.gen_range(0..4)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 2);
+        let wn = WaveletMatrixN::<u8, 2>::new(&numbers);
+        assert_eq!(wn.len(), wm.len());
+        for i in 0..wm.len() {
+            assert_eq!(wn.access(i), wm.access(i));
+        }
+        for c in 0..5u8 {
+            for k in (0..=wm.len() + 5).step_by(7) {
+                assert!(
+                    wn.rank(c, k) == wm.rank(c, k),
+                    "wn.rank({}, {}) == {}",
+                    c,
+                    k,
+                    wm.rank(c, k)
+                );
+            }
+            for k in (0..wm.rank(c, wm.len())).step_by(11) {
+                assert_eq!(wn.select(c, k), wm.select(c, k));
+            }
+        }
+        assert!(WaveletMatrixN::<u8, 2>::new([]).is_empty());
+    }
+}
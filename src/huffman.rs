@@ -0,0 +1,270 @@
+use alloc::collections::{BTreeMap, BinaryHeap};
+use alloc::vec::Vec;
+use core::cmp::{self, Reverse};
+
+use fid::{BitVector, FID};
+
+/// A wavelet tree whose shape follows the Huffman code of the symbols.
+///
+/// Frequent symbols get short root-to-leaf paths, so the total number of
+/// stored bits is close to the zeroth-order entropy of the text and queries on
+/// common symbols touch fewer bit vectors.
+pub struct HuffmanWaveletTree<T> {
+    nodes: Vec<Node<T>>,
+    root: Option<Child<T>>,
+    codes: BTreeMap<u64, Vec<bool>>,
+    len: u64,
+}
+
+struct Node<T> {
+    bv: BitVector,
+    children: [Child<T>; 2],
+}
+
+#[derive(Clone, Copy)]
+enum Child<T> {
+    Leaf(T),
+    Node(usize),
+}
+
+enum Shape {
+    Leaf(u64),
+    Internal(usize, usize),
+}
+
+impl<T> HuffmanWaveletTree<T>
+where
+    T: Into<u64> + Copy,
+{
+    pub fn new<K: AsRef<[T]>>(text: K) -> Self {
+        let text = text.as_ref();
+        let mut freq: BTreeMap<u64, (T, u64)> = BTreeMap::new();
+        for &c in text {
+            freq.entry(c.into()).or_insert((c, 0)).1 += 1;
+        }
+
+        let mut shapes = Vec::new();
+        let mut heap = BinaryHeap::new();
+        for (&n, &(_, f)) in &freq {
+            heap.push(Reverse((f, shapes.len())));
+            shapes.push(Shape::Leaf(n));
+        }
+        while heap.len() > 1 {
+            let Reverse((f0, i0)) = heap.pop().unwrap();
+            let Reverse((f1, i1)) = heap.pop().unwrap();
+            heap.push(Reverse((f0 + f1, shapes.len())));
+            shapes.push(Shape::Internal(i0, i1));
+        }
+
+        let mut codes = BTreeMap::new();
+        if let Some(Reverse((_, root))) = heap.pop() {
+            assign_codes(&shapes, root, &mut Vec::new(), &mut codes);
+        }
+
+        let mut tree = HuffmanWaveletTree {
+            nodes: Vec::new(),
+            root: None,
+            codes,
+            len: text.len() as u64,
+        };
+        if !shapes.is_empty() {
+            let root = shapes.len() - 1;
+            tree.root = Some(tree.build(&shapes, &freq, root, 0, text.to_vec()));
+        }
+        tree
+    }
+
+    fn build(
+        &mut self,
+        shapes: &[Shape],
+        freq: &BTreeMap<u64, (T, u64)>,
+        i: usize,
+        depth: usize,
+        text: Vec<T>,
+    ) -> Child<T> {
+        let (l, r) = match shapes[i] {
+            Shape::Leaf(n) => return Child::Leaf(freq[&n].0),
+            Shape::Internal(l, r) => (l, r),
+        };
+        let mut bv = BitVector::new();
+        let mut zeros = Vec::new();
+        let mut ones = Vec::new();
+        for c in text {
+            let bit = self.codes[&c.into()][depth];
+            bv.push(bit);
+            if bit {
+                ones.push(c);
+            } else {
+                zeros.push(c);
+            }
+        }
+        let left = self.build(shapes, freq, l, depth + 1, zeros);
+        let right = self.build(shapes, freq, r, depth + 1, ones);
+        self.nodes.push(Node {
+            bv,
+            children: [left, right],
+        });
+        Child::Node(self.nodes.len() - 1)
+    }
+
+    /// Returns the value at position `k`.
+    ///
+    /// `k` must be less than `len()`.
+    pub fn access(&self, k: u64) -> T {
+        let mut i = k;
+        let mut child = self.root.expect("access on an empty tree");
+        loop {
+            match child {
+                Child::Leaf(c) => return c,
+                Child::Node(n) => {
+                    let node = &self.nodes[n];
+                    let b = node.bv.get(i);
+                    i = node.bv.rank(b, i);
+                    child = node.children[b as usize];
+                }
+            }
+        }
+    }
+
+    /// Counts the occurrences of `c` in `text[0..k)`.
+    pub fn rank(&self, c: T, k: u64) -> u64 {
+        let code = match self.codes.get(&c.into()) {
+            Some(code) => code,
+            None => return 0,
+        };
+        let mut i = cmp::min(k, self.len);
+        let mut child = self.root;
+        for &b in code {
+            if let Some(Child::Node(n)) = child {
+                let node = &self.nodes[n];
+                i = node.bv.rank(b, i);
+                child = Some(node.children[b as usize]);
+            }
+        }
+        i
+    }
+
+    /// Returns the position of the (k+1)-th occurrence of `c`.
+    ///
+    /// The occurrence must exist, i.e. `k < rank(c, len())`.
+    pub fn select(&self, c: T, k: u64) -> u64 {
+        let code = &self.codes[&c.into()];
+        let mut path = Vec::with_capacity(code.len());
+        let mut child = self.root;
+        for &b in code {
+            if let Some(Child::Node(n)) = child {
+                path.push(n);
+                child = Some(self.nodes[n].children[b as usize]);
+            }
+        }
+        let mut i = k;
+        for (&n, &b) in path.iter().zip(code).rev() {
+            i = self.nodes[n].bv.select(b, i);
+        }
+        i
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the total number of bits stored over all nodes.
+    pub fn total_bits(&self) -> u64 {
+        self.nodes.iter().map(|node| node.bv.len()).sum()
+    }
+}
+
+fn assign_codes(
+    shapes: &[Shape],
+    i: usize,
+    code: &mut Vec<bool>,
+    codes: &mut BTreeMap<u64, Vec<bool>>,
+) {
+    match shapes[i] {
+        Shape::Leaf(n) => {
+            codes.insert(n, code.clone());
+        }
+        Shape::Internal(l, r) => {
+            code.push(false);
+            assign_codes(shapes, l, code, codes);
+            code.pop();
+            code.push(true);
+            assign_codes(shapes, r, code, codes);
+            code.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WaveletMatrix;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn same_answers_as_matrix() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        let wt = HuffmanWaveletTree::new(numbers);
+        assert_eq!(wt.len(), wm.len());
+        for i in 0..numbers.len() as u64 {
+            assert_eq!(wt.access(i), wm.access(i));
+        }
+        for c in 0..8u8 {
+            for k in 0..=numbers.len() as u64 + 2 {
+                assert!(
+                    wt.rank(c, k) == wm.rank(c, k),
+                    "wt.rank({}, {}) == {}",
+                    c,
+                    k,
+                    wm.rank(c, k)
+                );
+            }
+            for k in 0..wm.rank(c, wm.len()) {
+                assert_eq!(wt.select(c, k), wm.select(c, k));
+            }
+        }
+        assert_eq!(wt.rank(200, 12), 0);
+    }
+
+    #[test]
+    fn fewer_bits_on_skewed_input() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..2000)
+            .map(|_| {
+                if rng.gen_bool(0.9) {
+                    0
+                } else {
+                    rng.gen_range(1..8)
+                }
+            })
+            .collect();
+        let wm = WaveletMatrix::new_auto(&numbers);
+        let wt = HuffmanWaveletTree::new(&numbers);
+        assert!(wt.total_bits() < wm.len() * wm.bit_width());
+        for (i, &n) in numbers.iter().enumerate() {
+            assert_eq!(wt.access(i as u64), n);
+        }
+        for k in (0..wm.rank(0, wm.len())).step_by(97) {
+            assert_eq!(wt.select(0, k), wm.select(0, k));
+        }
+    }
+
+    #[test]
+    fn single_symbol_and_empty() {
+        let wt = HuffmanWaveletTree::new([3u8, 3, 3]);
+        assert_eq!(wt.total_bits(), 0);
+        assert_eq!(wt.access(1), 3);
+        assert_eq!(wt.rank(3, 2), 2);
+        assert_eq!(wt.select(3, 2), 2);
+
+        let wt = HuffmanWaveletTree::<u8>::new([]);
+        assert!(wt.is_empty());
+        assert_eq!(wt.rank(0, 10), 0);
+    }
+}
@@ -1,60 +1,550 @@
-use std::fmt;
+//! Wavelet matrices over integer symbols.
+//!
+//! The `std` feature is enabled by default and provides the `io`-based
+//! serialization and the `std::error::Error` impl. Disabling it only leaves
+//! out those APIs: `fid`, which provides the default row type `BitVector`,
+//! links `std`, so the crate cannot be used on `no_std` targets.
 
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+
+#[cfg(feature = "rkyv")]
+mod archive;
+mod backend;
+mod builder;
+mod error;
+mod fixed;
+mod huffman;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod quad;
+mod sampled;
+mod signed;
+mod weighted;
+mod words;
+
+#[cfg(feature = "rkyv")]
+pub use crate::archive::{ArchivableMatrix, ArchivedWaveletMatrix};
+pub use crate::backend::{FromOptions, HeapSize, PushBit, Shared};
+pub use crate::builder::Builder;
+pub use crate::error::WaveletError;
+pub use crate::fixed::WaveletMatrixN;
+pub use crate::huffman::HuffmanWaveletTree;
+#[cfg(feature = "mmap")]
+pub use crate::mmap::MmapBits;
+pub use crate::quad::QuadWaveletMatrix;
+pub use crate::sampled::Sampled;
+pub use crate::signed::{SignedSymbol, SignedWaveletMatrix};
+pub use crate::weighted::WeightedWaveletMatrix;
+#[cfg(feature = "rkyv")]
+pub use crate::words::ArchivedWordBits;
+pub use crate::words::{BuildOptions, WordBits};
+use alloc::collections::BinaryHeap;
+use core::cmp::{self, Reverse};
+use core::convert::TryFrom;
+use core::ops::{BitOr, Range, Shl};
 use fid::{BitVector, FID};
+#[cfg(feature = "mmap")]
+pub use memmap2::Mmap;
 use num_traits::Num;
-use std::ops::{BitOr, Shl};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "std")]
+const MAGIC: &[u8; 4] = b"WVMX";
+#[cfg(feature = "std")]
+const FORMAT_VERSION: u32 = 1;
+const ITER_CHUNK: u64 = 1024;
 
-pub struct WaveletMatrix<T> {
-    rows: Vec<BitVector>,
+/// A wavelet matrix over values of type `T`.
+///
+/// Each row is stored in a bit vector of type `B`, which defaults to
+/// `fid::BitVector`. Any type implementing `FID` can be used for queries; to
+/// build a matrix with it, the type must also implement `Default` and
+/// `PushBit` and the matrix is constructed with `new_with_backend`:
+///
+/// ```
+/// use fid::BitVector;
+/// use wavelet_tree::WaveletMatrix;
+///
+/// let wm = WaveletMatrix::<u8, BitVector>::new_with_backend(&[4u8, 7, 6, 5], 3);
+/// assert_eq!(wm.rank(6, 3), 1);
+/// ```
+///
+/// A matrix is immutable once built and is `Send + Sync` whenever `T` and `B`
+/// are, so it can be shared between threads behind an `Arc`. All the
+/// backends in this crate are `Send + Sync`.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(
+        bound(serialize = "B: Serialize", deserialize = "B: Deserialize<'de> + FID"),
+        try_from = "RawWaveletMatrix<B>"
+    )
+)]
+pub struct WaveletMatrix<T, B = BitVector> {
+    rows: Vec<B>,
     size: u64,
     len: u64,
     partitions: Vec<u64>,
-    _t: std::marker::PhantomData<T>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    _t: core::marker::PhantomData<T>,
 }
 
 impl<T> WaveletMatrix<T>
 where
     T: Into<u64> + Copy + Clone + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
 {
+    /// Builds a matrix with `size` bits per symbol.
+    ///
+    /// Bits of the values at or above `size` are silently dropped; use
+    /// `try_new_with_size` to reject such values instead. Panics if `size`
+    /// is greater than 64.
     pub fn new_with_size<K: AsRef<[T]>>(text: K, size: u64) -> Self {
+        Self::new_with_backend(text, size)
+    }
+
+    /// Same as `new_with_size`, but fails if a value does not fit in `size` bits.
+    pub fn try_new_with_size<K: AsRef<[T]>>(text: K, size: u64) -> Result<Self, WaveletError> {
+        if size > 64 {
+            return Err(WaveletError::SizeTooLarge(size));
+        }
+        if let Some(&c) = text.as_ref().iter().find(|&&c| !fits_in(c.into(), size)) {
+            return Err(WaveletError::SymbolTooLarge {
+                value: c.into(),
+                size,
+            });
+        }
+        Ok(Self::new_with_size(text, size))
+    }
+
+    pub fn new<K: AsRef<[T]>>(text: K) -> Self {
+        Self::new_with_size(text, core::mem::size_of::<T>() as u64 * 8)
+    }
+
+    /// Returns a `Builder` for `size`-bit values whose buffer reserves room
+    /// for `len` of them up front. See `Builder::with_capacity`.
+    pub fn with_capacity(len: u64, size: u64) -> Builder<T> {
+        Builder::with_capacity(len, size)
+    }
+
+    /// Builds a matrix with the minimal bit width able to represent the
+    /// largest value in `text` (at least one bit).
+    pub fn new_auto<K: AsRef<[T]>>(text: K) -> Self {
+        let max = text.as_ref().iter().map(|&c| c.into()).max().unwrap_or(0);
+        let size = cmp::max(64 - u64::from(max.leading_zeros()), 1);
+        Self::new_with_size(text, size)
+    }
+
+    /// Builds a matrix over the dense ranks `0..d` of the `d` distinct values
+    /// in `text`, using the minimal bit width.
+    ///
+    /// Returns the matrix together with the sorted table of distinct values.
+    /// Queries take and return ranks; use `map_symbol` and `unmap_symbol` to
+    /// translate between ranks and original values.
+    pub fn new_mapped<K: AsRef<[T]>>(text: K) -> (Self, Vec<T>) {
+        let text = text.as_ref();
+        let mut table = text.to_vec();
+        table.sort_by_key(|&c| c.into());
+        table.dedup_by_key(|c| (*c).into());
+        let ranks: Vec<T> = text
+            .iter()
+            .map(|&c| Self::map_symbol(&table, c).unwrap())
+            .collect();
+        (Self::new_auto(ranks), table)
+    }
+
+    /// Returns the rank of `c` in a table returned by `new_mapped`, or `None`
+    /// if `c` does not occur in it.
+    pub fn map_symbol(table: &[T], c: T) -> Option<T> {
+        let n = c.into();
+        table
+            .binary_search_by_key(&n, |&t| t.into())
+            .ok()
+            .map(|i| from_bits(i as u64, 64))
+    }
+
+    /// Returns the original value of the rank `r` in a table returned by `new_mapped`.
+    pub fn unmap_symbol(table: &[T], r: T) -> T {
+        table[r.into() as usize]
+    }
+
+    /// Builds the matrix of the text of `a` followed by the text of `b`.
+    ///
+    /// Every row of the result interleaves elements of both inputs, so the
+    /// rows cannot be appended. Both texts are decoded and the matrix is
+    /// rebuilt, which takes `O((n + m) · size)` time and `O(n + m)` extra
+    /// space. Panics if `a` and `b` have different bit widths.
+    pub fn concat(a: &Self, b: &Self) -> Self {
+        assert_eq!(
+            a.size, b.size,
+            "concat: both matrices must have the same bit width"
+        );
+        let mut text = a.to_vec();
+        text.extend(b.iter());
+        Self::new_with_size(text, a.size)
+    }
+
+    /// Builds a matrix with `size` bits per symbol over the text in which each
+    /// `(c, n)` of `runs` stands for `n` consecutive copies of `c`.
+    ///
+    /// The text is never expanded: every level partitions the runs rather than
+    /// the symbols, so only the rows grow with the length of the text. Panics
+    /// if `size` is greater than 64.
+    pub fn from_runs(runs: &[(T, u64)], size: u64) -> Self {
+        assert!(size <= 64, "size ({}) must be at most 64", size);
+        let mut rows: Vec<BitVector> = Vec::with_capacity(size as usize);
+        let mut partitions: Vec<u64> = Vec::with_capacity(size as usize);
+        let mut cur: Vec<(T, u64)> = runs.iter().filter(|&&(_, n)| n > 0).cloned().collect();
+        for r in 0..size {
+            let shift = size - r - 1;
+            let mut bv = BitVector::new();
+            let mut z = 0;
+            for &(c, n) in &cur {
+                let bit = (c.into() >> shift) & 1 > 0;
+                for _ in 0..n {
+                    bv.push(bit);
+                }
+                if !bit {
+                    z += n;
+                }
+            }
+            let (mut zeros, ones): (Vec<_>, Vec<_>) = cur
+                .into_iter()
+                .partition(|&(c, _)| (c.into() >> shift) & 1 == 0);
+            rows.push(bv);
+            partitions.push(z);
+            zeros.extend(ones);
+            cur = zeros;
+        }
+        WaveletMatrix {
+            rows,
+            size,
+            len: runs.iter().map(|&(_, n)| n).sum(),
+            partitions,
+            _t: core::marker::PhantomData,
+        }
+    }
+
+    /// Same as `new_with_size`, but extracts the bits and partitions each row
+    /// in parallel.
+    #[cfg(feature = "rayon")]
+    pub fn new_par<K: AsRef<[T]>>(text: K, size: u64) -> Self
+    where
+        T: Send + Sync,
+    {
         let mut rows: Vec<BitVector> = vec![];
-        let mut zeros: Vec<T> = text.as_ref().to_vec();
-        let mut ones: Vec<T> = Vec::new();
         let mut partitions: Vec<u64> = Vec::new();
+        let mut cur: Vec<T> = text.as_ref().to_vec();
         for r in 0..size {
+            let shift = size - r - 1;
+            let bits: Vec<bool> = cur
+                .par_iter()
+                .map(|&c| (c.into() >> shift) & 1 > 0)
+                .collect();
             let mut bv = BitVector::new();
-            let mut new_zeros: Vec<T> = Vec::new();
-            let mut new_ones: Vec<T> = Vec::new();
-            for arr in &[zeros, ones] {
-                for &c in arr {
-                    let b = c.into();
-                    let bit = (b >> (size - r - 1)) & 1 > 0;
+            for &bit in &bits {
+                bv.push(bit);
+            }
+            let (mut zeros, ones): (Vec<T>, Vec<T>) = cur
+                .par_iter()
+                .zip(bits.par_iter())
+                .partition_map(|(&c, &bit)| {
                     if bit {
-                        new_ones.push(c);
+                        rayon::iter::Either::Right(c)
                     } else {
-                        new_zeros.push(c);
+                        rayon::iter::Either::Left(c)
                     }
-                    bv.push(bit);
-                }
-            }
-            zeros = new_zeros;
-            ones = new_ones;
+                });
             rows.push(bv);
             partitions.push(zeros.len() as u64);
+            zeros.extend(ones);
+            cur = zeros;
         }
         WaveletMatrix {
-            rows: rows,
-            size: size,
+            rows,
+            size,
             len: text.as_ref().len() as u64,
-            partitions: partitions,
-            _t: std::marker::PhantomData::<T>,
+            partitions,
+            _t: core::marker::PhantomData,
         }
     }
+}
 
-    pub fn new<K: AsRef<[T]>>(text: K) -> Self {
-        Self::new_with_size(text, std::mem::size_of::<T>() as u64 * 8)
+impl WaveletMatrix<u32> {
+    /// Builds a matrix over the code points of the chars in `s`.
+    ///
+    /// This uses the full 32-bit width of `u32`; combine with `new_mapped` on
+    /// the code points for a matrix sized to the distinct chars instead.
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Self {
+        Self::new(s.chars().map(u32::from).collect::<Vec<u32>>())
+    }
+
+    /// Counts the occurrences of `c` in the first `k` chars.
+    pub fn rank_char(&self, c: char, k: u64) -> u64 {
+        self.rank(u32::from(c), k)
+    }
+}
+
+impl WaveletMatrix<u8> {
+    /// Builds a 2-bit matrix over a DNA sequence, mapping `A`, `C`, `G` and `T`
+    /// to 0, 1, 2 and 3. Lowercase bases are accepted too.
+    ///
+    /// Any other byte fails with `WaveletError::InvalidNucleotide`.
+    pub fn from_dna(seq: &[u8]) -> Result<Self, WaveletError> {
+        let codes = seq
+            .iter()
+            .enumerate()
+            .map(|(i, &byte)| {
+                nucleotide_code(byte).ok_or(WaveletError::InvalidNucleotide {
+                    byte,
+                    position: i as u64,
+                })
+            })
+            .collect::<Result<Vec<u8>, _>>()?;
+        Ok(Self::new_with_size(codes, 2))
+    }
+
+    /// Counts the occurrences of the ASCII nucleotide `base` in the first `k`
+    /// positions of a matrix built by `from_dna`.
+    pub fn rank_base(&self, base: u8, k: u64) -> u64 {
+        nucleotide_code(base).map_or(0, |c| self.rank(c, k))
+    }
+
+    /// Returns the position of the (k+1)-th occurrence of the ASCII nucleotide
+    /// `base` in a matrix built by `from_dna`, or `None` if there is no such
+    /// occurrence.
+    pub fn select_base(&self, base: u8, k: u64) -> Option<u64> {
+        nucleotide_code(base).and_then(|c| self.try_select(c, k))
+    }
+}
+
+fn nucleotide_code(base: u8) -> Option<u8> {
+    match base {
+        b'A' | b'a' => Some(0),
+        b'C' | b'c' => Some(1),
+        b'G' | b'g' => Some(2),
+        b'T' | b't' => Some(3),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "std")]
+impl WaveletMatrix<u64> {
+    /// Builds a matrix with `size` bits per symbol from whitespace-separated
+    /// decimal integers read from `r`.
+    ///
+    /// The input is parsed one line at a time. A token that is not a `u64`
+    /// fails with `io::ErrorKind::InvalidData`; a value that does not fit in
+    /// `size` bits, or a `size` above 64, fails with a wrapped `WaveletError`.
+    pub fn from_reader<R: io::BufRead>(r: R, size: u64) -> io::Result<Self> {
+        if size > 64 {
+            return Err(WaveletError::SizeTooLarge(size).into());
+        }
+        let mut builder = Builder::new(size);
+        for line in r.lines() {
+            for token in line?.split_whitespace() {
+                let value: u64 = token.parse().map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid integer {:?}: {}", token, e),
+                    )
+                })?;
+                if !fits_in(value, size) {
+                    return Err(WaveletError::SymbolTooLarge { value, size }.into());
+                }
+                builder.push(value);
+            }
+        }
+        Ok(builder.build())
+    }
+}
+
+impl<T, B> WaveletMatrix<T, B>
+where
+    T: Into<u64> + Copy + Clone + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
+    B: FID + Default + PushBit,
+{
+    /// Same as `new_with_backend`, but rows with identical bits share one
+    /// allocation.
+    ///
+    /// Identical rows only arise from degenerate texts, e.g. one where every
+    /// position holds the same value. Queries see the shared rows as usual.
+    ///
+    /// Each row is compared with the distinct rows before it as soon as its
+    /// bits are set, and dropped in favor of an identical one. A comparison
+    /// checks the number of ones first and then the bits, so the worst case is
+    /// `O(size² · len)`, for many rows with the same number of ones.
+    pub fn new_dedup<K: AsRef<[T]>>(text: K, size: u64) -> WaveletMatrix<T, Shared<B>> {
+        Self::new_with_rows(text, size, B::default, |rows: &[Shared<B>], bv| {
+            rows.iter()
+                .find(|row| same_bits(&*row.0, &bv))
+                .cloned()
+                .unwrap_or_else(|| Shared(Arc::new(bv)))
+        })
+    }
+
+    /// Builds a matrix with `size` bits per symbol using `B` for the rows.
+    pub fn new_with_backend<K: AsRef<[T]>>(text: K, size: u64) -> Self {
+        Self::new_with_rows(text, size, B::default, |_, bv| bv)
+    }
+
+    /// Builds a matrix with `size` bits per symbol whose rows are indexed as
+    /// described by `opts`.
+    ///
+    /// `BuildOptions::default()` builds the same rows as `new_with_backend`.
+    /// This needs a backend that applies the options, such as `WordBits`;
+    /// `fid::BitVector`, the default backend, has a fixed index layout and
+    /// cannot be built this way.
+    pub fn new_with_size_and_opts<K: AsRef<[T]>>(text: K, size: u64, opts: BuildOptions) -> Self
+    where
+        B: FromOptions,
+    {
+        Self::new_with_rows(text, size, || B::from_options(opts), |_, bv| bv)
+    }
+
+    // Builds the matrix with the empty rows created by `new_row`. Once the
+    // bits of a row are set, `finish_row` turns it into the stored row, given
+    // the rows stored so far.
+    fn new_with_rows<K, R, F, G>(
+        text: K,
+        size: u64,
+        mut new_row: F,
+        mut finish_row: G,
+    ) -> WaveletMatrix<T, R>
+    where
+        K: AsRef<[T]>,
+        F: FnMut() -> B,
+        G: FnMut(&[R], B) -> R,
+    {
+        assert!(size <= 64, "size ({}) must be at most 64", size);
+        let mut rows: Vec<R> = Vec::with_capacity(size as usize);
+        let mut partitions: Vec<u64> = Vec::with_capacity(size as usize);
+        let mut cur: Vec<T> = text.as_ref().to_vec();
+        let mut visited: Vec<u64> = vec![0; cur.len().div_ceil(64)];
+        for r in 0..size {
+            let mut bv = new_row();
+            for &c in &cur {
+                bv.push_bit((c.into() >> (size - r - 1)) & 1 > 0);
+            }
+            let z = bv.rank0(bv.len());
+            // Stable partition by the current bit, applied in place by following
+            // the cycles of the permutation. Destinations come from `bv` itself.
+            for w in visited.iter_mut() {
+                *w = 0;
+            }
+            let dest = |j: usize| {
+                let j = j as u64;
+                if bv.get(j) {
+                    (z + bv.rank1(j)) as usize
+                } else {
+                    bv.rank0(j) as usize
+                }
+            };
+            for i in 0..cur.len() {
+                if visited[i / 64] & (1 << (i % 64)) != 0 {
+                    continue;
+                }
+                let mut j = i;
+                let mut v = cur[i];
+                loop {
+                    visited[j / 64] |= 1 << (j % 64);
+                    let d = dest(j);
+                    if d == i {
+                        cur[i] = v;
+                        break;
+                    }
+                    core::mem::swap(&mut v, &mut cur[d]);
+                    j = d;
+                }
+            }
+            let row = finish_row(&rows, bv);
+            rows.push(row);
+            partitions.push(z);
+        }
+        WaveletMatrix {
+            rows,
+            size,
+            len: text.as_ref().len() as u64,
+            partitions,
+            _t: core::marker::PhantomData,
+        }
+    }
+
+    /// Reads a matrix written by `write_to`.
+    ///
+    /// Malformed or truncated input is reported as an `io::Error` wrapping a
+    /// `WaveletError`. The result is checked with `validate`.
+    #[cfg(feature = "std")]
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut magic = [0u8; 4];
+        read_exact(r, &mut magic)?;
+        if &magic != MAGIC {
+            return Err(WaveletError::InvalidFormat("bad magic bytes").into());
+        }
+        let mut version = [0u8; 4];
+        read_exact(r, &mut version)?;
+        if u32::from_le_bytes(version) != FORMAT_VERSION {
+            return Err(WaveletError::InvalidFormat("unsupported format version").into());
+        }
+        let size = read_u64(r)?;
+        check_size::<T>(size)?;
+        let len = read_u64(r)?;
+        let mut partitions = Vec::with_capacity(size as usize);
+        for _ in 0..size {
+            partitions.push(read_u64(r)?);
+        }
+        let mut rows = Vec::with_capacity(size as usize);
+        for _ in 0..size {
+            let mut bv = B::default();
+            let mut word = 0u64;
+            for i in 0..len {
+                if i % 64 == 0 {
+                    word = read_u64(r)?;
+                }
+                bv.push_bit((word >> (i % 64)) & 1 > 0);
+            }
+            rows.push(bv);
+        }
+        let wm = WaveletMatrix {
+            rows,
+            size,
+            len,
+            partitions,
+            _t: core::marker::PhantomData,
+        };
+        wm.validate()
+            .map_err(|_| WaveletError::InvalidFormat(INCONSISTENT))?;
+        Ok(wm)
     }
+}
 
+impl<T, B> WaveletMatrix<T, B>
+where
+    T: Into<u64> + Copy + Clone + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
+    B: FID,
+{
+    /// Returns the value at position `k`.
+    ///
+    /// `k` must be less than `len()`; otherwise this panics or returns a
+    /// meaningless value. Use `get` for a checked variant.
+    ///
+    /// The value is rebuilt by shifting bits into a `T`, so a set bit above the
+    /// width of `T` would overflow the shift. A matrix built from a `&[T]`
+    /// never holds such bits, and `read_from` and deserialization reject data
+    /// whose `size` is wider than `T`. Use `access_as` to convert values to
+    /// another type.
     pub fn access(&self, k: u64) -> T {
         let mut i = k;
         let mut n = T::zero();
@@ -70,24 +560,384 @@ where
         n
     }
 
+    /// Returns the value at position `k` converted to `U`, or `None` if it
+    /// does not fit in `U`.
+    ///
+    /// The value is assembled as a `u64`, so this does not depend on the
+    /// width of `T`. `k` must be less than `len()`.
+    pub fn access_as<U: TryFrom<u64>>(&self, k: u64) -> Option<U> {
+        let mut i = k;
+        let mut n = 0u64;
+        for (r, bv) in self.rows.iter().enumerate() {
+            if bv.get(i) {
+                i = self.partitions[r] + bv.rank1(i);
+                n |= 1 << (self.size - (r as u64) - 1);
+            } else {
+                i = bv.rank0(i);
+            }
+        }
+        U::try_from(n).ok()
+    }
+
+    /// Returns the value `c` at position `k` together with `rank(c, k + 1)`,
+    /// the number of occurrences of `c` in `text[0..=k]`.
+    ///
+    /// Both are found in a single descent. `k` must be less than `len()`.
+    pub fn access_rank(&self, k: u64) -> (T, u64) {
+        let mut i = k;
+        let mut s = 0;
+        let mut n = T::zero();
+        for (r, bv) in self.rows.iter().enumerate() {
+            if bv.get(i) {
+                let z = self.partitions[r];
+                i = z + bv.rank1(i);
+                s = z + bv.rank1(s);
+                n = n | (T::one() << (self.size - (r as u64) - 1));
+            } else {
+                i = bv.rank0(i);
+                s = bv.rank0(s);
+            }
+        }
+        (n, i - s + 1)
+    }
+
+    /// Counts the positions before `k` holding the same value as position `k`,
+    /// i.e. `rank(access(k), k)`, in a single descent.
+    ///
+    /// `k` must be less than `len()`.
+    pub fn intra_rank(&self, k: u64) -> u64 {
+        self.access_rank(k).1 - 1
+    }
+
+    /// Returns the value `c` at position `k` together with `rank(c, len())`,
+    /// the number of occurrences of `c` in the whole text.
+    ///
+    /// The descent follows both ends of the node holding `k`, which at the
+    /// bottom span exactly the occurrences of `c`. `k` must be less than
+    /// `len()`.
+    pub fn access_with_total(&self, k: u64) -> (T, u64) {
+        let mut i = k;
+        let mut s = 0;
+        let mut e = self.len;
+        let mut n = T::zero();
+        for (r, bv) in self.rows.iter().enumerate() {
+            let b = bv.get(i);
+            i = bv.rank(b, i);
+            s = bv.rank(b, s);
+            e = bv.rank(b, e);
+            if b {
+                let z = self.partitions[r];
+                i += z;
+                s += z;
+                e += z;
+                n = n | (T::one() << (self.size - (r as u64) - 1));
+            }
+        }
+        (n, e - s)
+    }
+
+    /// Returns where position `k` lands when the text is stably sorted by value.
+    ///
+    /// This is the number of smaller values plus the number of equal values
+    /// before `k`. The bottom of the `access` descent is not enough as the
+    /// last level orders values by their reversed bits. `k` must be less than
+    /// `len()`.
+    pub fn sorted_rank(&self, k: u64) -> u64 {
+        let (c, r) = self.access_rank(k);
+        self.count_relative(c, 0..self.len).0 + r - 1
+    }
+
+    /// Returns the value at position `k`, panicking if `k >= len()`.
+    ///
+    /// This is the checked counterpart of `wm[k]`. `Index` is not implemented
+    /// because values are decoded on demand and there is no stored `T` to
+    /// return a reference to.
+    pub fn at(&self, k: u64) -> T {
+        assert!(
+            k < self.len,
+            "index out of bounds: the len is {} but the index is {}",
+            self.len,
+            k
+        );
+        self.access(k)
+    }
+
+    /// Returns the values at each of `positions`, in order.
+    ///
+    /// Panics if any position is not less than `len()`.
+    pub fn access_batch(&self, positions: &[u64]) -> Vec<T> {
+        positions.iter().map(|&k| self.at(k)).collect()
+    }
+
+    /// Returns an iterator over the values `text[0], text[1], ...`.
+    pub fn iter(&self) -> Iter<'_, T, B> {
+        Iter {
+            wm: self,
+            pos: 0,
+            end: self.len,
+            buf: Vec::new().into_iter(),
+        }
+    }
+
+    /// Iterates over the positions in `range` paired with their values.
+    ///
+    /// Values are decoded in chunks like `iter`, not one `access` at a time.
+    pub fn range_iter(&self, range: Range<u64>) -> impl Iterator<Item = (u64, T)> + '_ {
+        let e = cmp::min(range.end, self.len);
+        let s = cmp::min(range.start, e);
+        let values = Iter {
+            wm: self,
+            pos: s,
+            end: e,
+            buf: Vec::new().into_iter(),
+        };
+        (s..e).zip(values)
+    }
+
+    /// Returns the decoded sequence.
+    pub fn to_vec(&self) -> Vec<T> {
+        self.iter().collect()
+    }
+
+    /// Appends the decoded values of `text[range]` to `out`.
+    ///
+    /// Reusing `out` across calls saves allocating the result; the decoding
+    /// itself still needs scratch space proportional to the range.
+    pub fn decode_into(&self, range: Range<u64>, out: &mut Vec<T>) {
+        let e = cmp::min(range.end, self.len);
+        let s = cmp::min(range.start, e);
+        out.reserve((e - s) as usize);
+        self.decode_range(s, e, out);
+    }
+
+    /// Rebuilds the matrix with the minimal bit width able to represent its
+    /// largest value, like `new_auto`.
+    pub fn shrink_to_fit(&self) -> WaveletMatrix<T> {
+        WaveletMatrix::new_auto(self.to_vec())
+    }
+
+    // Decodes `text[s..e)` level by level. Elements sharing the same upper bits
+    // stay contiguous and in order at each level, so each such run is read
+    // sequentially and only its start position needs to be ranked.
+    fn decode_range(&self, s: u64, e: u64, out: &mut Vec<T>) {
+        let mut vals = vec![0u64; (e - s) as usize];
+        let mut runs = vec![(s, (0..vals.len()).collect::<Vec<usize>>())];
+        for (r, bv) in self.rows.iter().enumerate() {
+            let z = self.partitions[r];
+            let bit = 1 << (self.size - (r as u64) - 1);
+            let mut next_runs = Vec::with_capacity(runs.len() * 2);
+            for (start, idx) in runs {
+                let mut zeros = Vec::new();
+                let mut ones = Vec::new();
+                for (j, &o) in idx.iter().enumerate() {
+                    if bv.get(start + j as u64) {
+                        vals[o] |= bit;
+                        ones.push(o);
+                    } else {
+                        zeros.push(o);
+                    }
+                }
+                if !zeros.is_empty() {
+                    next_runs.push((bv.rank0(start), zeros));
+                }
+                if !ones.is_empty() {
+                    next_runs.push((z + bv.rank1(start), ones));
+                }
+            }
+            runs = next_runs;
+        }
+        out.extend(vals.into_iter().map(|n| self.value_of(n)));
+    }
+
+    /// Returns the value at position `k`, or `None` if `k >= len()`.
+    pub fn get(&self, k: u64) -> Option<T> {
+        if k < self.len {
+            Some(self.access(k))
+        } else {
+            None
+        }
+    }
+
+    /// Same as `get`, but reports an out-of-range `k` as an error.
+    pub fn try_access(&self, k: u64) -> Result<T, WaveletError> {
+        self.get(k).ok_or(WaveletError::IndexOutOfBounds {
+            index: k,
+            len: self.len,
+        })
+    }
+
+    /// Counts the occurrences of `c` in `text[0..k)`.
+    ///
+    /// A `c` with bits set at or above the bit width never occurs, so it
+    /// counts 0. Use `try_rank` to report such symbols as an error.
     pub fn rank(&self, c: T, k: u64) -> u64 {
+        self.rank_range(c, 0..k)
+    }
+
+    /// Same as `rank`, but reports a `c` with bits set at or above the bit
+    /// width as an error instead of counting 0.
+    pub fn try_rank(&self, c: T, k: u64) -> Result<u64, WaveletError> {
         let n = c.into();
-        let mut s = 0u64;
-        let mut e = if k < self.len { k } else { self.len };
+        if fits_in(n, self.size) {
+            Ok(self.rank(c, k))
+        } else {
+            Err(WaveletError::SymbolTooLarge {
+                value: n,
+                size: self.size,
+            })
+        }
+    }
+
+    /// Counts the occurrences of `c` in `text[range]`, or 0 if `c` is wider
+    /// than the bit width.
+    pub fn rank_range(&self, c: T, range: Range<u64>) -> u64 {
+        let n = c.into();
+        if !fits_in(n, self.size) {
+            return 0;
+        }
+        let mut e = cmp::min(range.end, self.len);
+        let mut s = cmp::min(range.start, e);
         for (r, bv) in self.rows.iter().enumerate() {
             let b = (n >> (self.size - (r as u64) - 1)) & 1 > 0;
             s = bv.rank(b, s);
             e = bv.rank(b, e);
             if b {
                 let z = self.partitions[r];
-                s = s + z;
-                e = e + z;
+                s += z;
+                e += z;
             }
         }
         e - s
     }
 
+    /// Returns whether `c` occurs anywhere in the text.
+    ///
+    /// The descent stops as soon as the interval of `c` becomes empty.
+    pub fn contains(&self, c: T) -> bool {
+        let n = c.into();
+        if !fits_in(n, self.size) {
+            return false;
+        }
+        let mut s = 0;
+        let mut e = self.len;
+        for (r, bv) in self.rows.iter().enumerate() {
+            if s == e {
+                return false;
+            }
+            let b = (n >> (self.size - (r as u64) - 1)) & 1 > 0;
+            s = bv.rank(b, s);
+            e = bv.rank(b, e);
+            if b {
+                let z = self.partitions[r];
+                s += z;
+                e += z;
+            }
+        }
+        s < e
+    }
+
+    /// Precomputes the descent path of `c` so that repeated `rank` queries for
+    /// it are cheaper.
+    pub fn prepared_rank(&self, c: T) -> PreparedRank<'_, B> {
+        let n = c.into();
+        let mut steps = Vec::with_capacity(self.rows.len());
+        let mut start = 0;
+        let fits = fits_in(n, self.size);
+        if fits {
+            for (r, bv) in self.rows.iter().enumerate() {
+                let b = (n >> (self.size - (r as u64) - 1)) & 1 > 0;
+                let offset = if b { self.partitions[r] } else { 0 };
+                start = bv.rank(b, start) + offset;
+                steps.push((b, offset));
+            }
+        }
+        PreparedRank {
+            rows: &self.rows,
+            steps,
+            start,
+            len: self.len,
+            fits,
+        }
+    }
+
+    /// Alias of `rank_range`.
+    pub fn count(&self, c: T, range: Range<u64>) -> u64 {
+        self.rank_range(c, range)
+    }
+
+    /// Answers `rank(c, k)` for every `(c, k)` in `queries`, in order.
+    ///
+    /// Queries are grouped by symbol so that each group shares a single descent.
+    pub fn rank_batch(&self, queries: &[(T, u64)]) -> Vec<u64> {
+        let mut order: Vec<usize> = (0..queries.len()).collect();
+        order.sort_by_key(|&i| queries[i].0.into());
+        let mut result = vec![0; queries.len()];
+        for group in order.chunk_by(|&i, &j| queries[i].0.into() == queries[j].0.into()) {
+            let n = queries[group[0]].0.into();
+            let mut s = 0u64;
+            let mut ends: Vec<u64> = group
+                .iter()
+                .map(|&i| cmp::min(queries[i].1, self.len))
+                .collect();
+            for (r, bv) in self.rows.iter().enumerate() {
+                let b = (n >> (self.size - (r as u64) - 1)) & 1 > 0;
+                let z = if b { self.partitions[r] } else { 0 };
+                s = z + bv.rank(b, s);
+                for e in ends.iter_mut() {
+                    *e = z + bv.rank(b, *e);
+                }
+            }
+            for (&i, &e) in group.iter().zip(&ends) {
+                result[i] = e - s;
+            }
+        }
+        result
+    }
+
+    /// Answers `rank(c, p)` for every `p` in `positions`, in order.
+    ///
+    /// The positions are sorted and carried down a single descent for `c`.
+    /// Rank preserves their order, so each row is then probed at increasing
+    /// positions.
+    pub fn ranks_at(&self, c: T, positions: &[u64]) -> Vec<u64> {
+        let n = c.into();
+        let mut result = vec![0; positions.len()];
+        if !fits_in(n, self.size) {
+            return result;
+        }
+        let mut order: Vec<usize> = (0..positions.len()).collect();
+        order.sort_by_key(|&i| positions[i]);
+        let mut s = 0u64;
+        let mut ends: Vec<u64> = order
+            .iter()
+            .map(|&i| cmp::min(positions[i], self.len))
+            .collect();
+        for (r, bv) in self.rows.iter().enumerate() {
+            let b = (n >> (self.size - (r as u64) - 1)) & 1 > 0;
+            let z = if b { self.partitions[r] } else { 0 };
+            s = z + bv.rank(b, s);
+            for e in ends.iter_mut() {
+                *e = z + bv.rank(b, *e);
+            }
+        }
+        for (&i, &e) in order.iter().zip(&ends) {
+            result[i] = e - s;
+        }
+        result
+    }
+
+    /// Returns the position of the (k+1)-th occurrence of `c`.
+    ///
+    /// The occurrence must exist, i.e. `k < rank(c, len())`; otherwise the
+    /// result is meaningless. Debug builds check this. Use `try_select` when
+    /// the occurrence may be missing.
     pub fn select(&self, c: T, k: u64) -> u64 {
+        debug_assert!(
+            k < self.rank(c, self.len),
+            "select: k ({}) must be less than the number of occurrences ({})",
+            k,
+            self.rank(c, self.len)
+        );
         let n = c.into();
         let mut s = 0u64;
         for (r, bv) in self.rows.iter().enumerate() {
@@ -111,44 +961,1272 @@ where
         e
     }
 
-    pub fn len(&self) -> u64 {
-        self.len
+    /// Returns the position of the (k+1)-th occurrence of `c`, or `None` if
+    /// `c` occurs `k` times or fewer.
+    pub fn try_select(&self, c: T, k: u64) -> Option<u64> {
+        if self.rank(c, self.len) > k {
+            Some(self.select(c, k))
+        } else {
+            None
+        }
     }
-}
 
-impl<T: fmt::Debug> fmt::Debug for WaveletMatrix<T> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let len = self.rows[0].len();
-        writeln!(f, "WaveletMatrix {{")?;
-        for bv in &self.rows {
-            write!(f, "  ")?;
-            for i in 0..len {
-                write!(f, "{}", if bv.get(i) { "1" } else { "0" })?;
-            }
-            writeln!(f, "")?;
+    /// Returns the position of the (k+1)-th occurrence of `c` at or after
+    /// position `p`, or `None` if there is no such occurrence.
+    pub fn select_after(&self, c: T, p: u64, k: u64) -> Option<u64> {
+        self.rank(c, p)
+            .checked_add(k)
+            .and_then(|k| self.try_select(c, k))
+    }
+
+    /// Returns the position of the (k+1)-th occurrence of `c` within
+    /// `text[range]`, or `None` if there is no such occurrence.
+    pub fn select_kth_in_range(&self, c: T, range: Range<u64>, k: u64) -> Option<u64> {
+        self.select_after(c, range.start, k)
+            .filter(|&p| p < range.end)
+    }
+
+    /// Returns the last position before `p` holding `c`.
+    pub fn prev_occurrence(&self, c: T, p: u64) -> Option<u64> {
+        match self.rank(c, p) {
+            0 => None,
+            r => Some(self.select(c, r - 1)),
         }
-        write!(f, "}}")
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    #[test]
-    fn rank_small() {
-        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
-        let size = 3;
-        let wm = WaveletMatrix::new_with_size(numbers, size);
-        assert_eq!(wm.len, numbers.len() as u64);
-        for i in 0..(1 << size) {
-            let mut r = 0;
-            for (k, &n) in numbers.iter().enumerate() {
-                assert!(
-                    wm.rank(i as u8, k as u64) == r,
-                    "wm.rank({}, {}) == {}",
-                    i,
-                    k,
-                    r
+    /// Returns the first position after `p` holding `c`.
+    pub fn next_occurrence(&self, c: T, p: u64) -> Option<u64> {
+        p.checked_add(1)
+            .and_then(|q| self.try_select(c, self.rank(c, q)))
+    }
+
+    /// Returns the positions of all occurrences of `c` in `text[range]`, in
+    /// ascending order.
+    pub fn positions(&self, c: T, range: Range<u64>) -> Vec<u64> {
+        let first = self.rank(c, range.start);
+        let last = self.rank(c, range.end);
+        (first..last).map(|k| self.select(c, k)).collect()
+    }
+
+    /// Yields `rank(c, 1), rank(c, 2), ..., rank(c, len())`.
+    ///
+    /// The occurrences of `c` are located up front in one pass over the levels,
+    /// which takes `O(m · size)` select operations for `m` occurrences. The
+    /// scan then only advances a cursor over them and never decodes the text.
+    pub fn rank_scan(&self, c: T) -> impl Iterator<Item = u64> + '_ {
+        let occurrences = self.occurrences(c);
+        let mut count = 0;
+        (0..self.len).map(move |k| {
+            if occurrences.get(count) == Some(&k) {
+                count += 1;
+            }
+            count as u64
+        })
+    }
+
+    // Returns the positions of every occurrence of `c` in ascending order.
+    //
+    // The interval of `c` at the bottom is found with one descent, and all of
+    // its positions are then mapped back up together, one level at a time.
+    // The partitions are stable, so the order is kept at every level.
+    fn occurrences(&self, c: T) -> Vec<u64> {
+        let n = c.into();
+        if !fits_in(n, self.size) {
+            return Vec::new();
+        }
+        let mut s = 0;
+        let mut e = self.len;
+        for (r, bv) in self.rows.iter().enumerate() {
+            let b = (n >> (self.size - (r as u64) - 1)) & 1 > 0;
+            s = bv.rank(b, s);
+            e = bv.rank(b, e);
+            if b {
+                let z = self.partitions[r];
+                s += z;
+                e += z;
+            }
+        }
+        let mut positions: Vec<u64> = (s..e).collect();
+        for (r, bv) in self.rows.iter().enumerate().rev() {
+            let b = (n >> (self.size - (r as u64) - 1)) & 1 > 0;
+            let z = self.partitions[r];
+            for p in positions.iter_mut() {
+                *p = if b {
+                    bv.select1(*p - z)
+                } else {
+                    bv.select0(*p)
+                };
+            }
+        }
+        positions
+    }
+
+    /// Returns the length of the longest run of consecutive positions holding
+    /// `c`, or 0 if `c` does not occur.
+    ///
+    /// Each occurrence costs one `select`; a run ending at `p` is extended
+    /// while the next occurrence is at `p + 1`.
+    pub fn longest_run(&self, c: T) -> u64 {
+        let mut longest = 0;
+        let mut run = 0;
+        let mut prev = None;
+        for k in 0..self.rank(c, self.len) {
+            let p = self.select(c, k);
+            run = if prev == Some(p.wrapping_sub(1)) {
+                run + 1
+            } else {
+                1
+            };
+            longest = cmp::max(longest, run);
+            prev = Some(p);
+        }
+        longest
+    }
+
+    /// Returns the (k+1)-th smallest value in `text[range]`.
+    ///
+    /// Panics if `k` is not less than the length of the range.
+    pub fn quantile(&self, range: Range<u64>, k: u64) -> T {
+        let mut e = cmp::min(range.end, self.len);
+        let mut s = cmp::min(range.start, e);
+        assert!(
+            k < e - s,
+            "quantile: k ({}) must be less than the range length ({})",
+            k,
+            e - s
+        );
+        let mut k = k;
+        let mut n = T::zero();
+        for (r, bv) in self.rows.iter().enumerate() {
+            let zs = bv.rank0(s);
+            let ze = bv.rank0(e);
+            let zeros = ze - zs;
+            if k < zeros {
+                s = zs;
+                e = ze;
+            } else {
+                k -= zeros;
+                let z = self.partitions[r];
+                s = z + bv.rank1(s);
+                e = z + bv.rank1(e);
+                n = n | (T::one() << (self.size - (r as u64) - 1));
+            }
+        }
+        n
+    }
+
+    /// Answers `quantile(range, k)` for every `(range, k)` in `queries`, in
+    /// order.
+    ///
+    /// Panics under the same conditions as `quantile`.
+    pub fn quantile_batch(&self, queries: &[(Range<u64>, u64)]) -> Vec<T> {
+        queries
+            .iter()
+            .map(|(range, k)| self.quantile(range.clone(), *k))
+            .collect()
+    }
+
+    /// Same as `quantile`, but reports `k` not less than the length of the
+    /// range as an error.
+    pub fn try_quantile(&self, range: Range<u64>, k: u64) -> Result<T, WaveletError> {
+        let e = cmp::min(range.end, self.len);
+        let s = cmp::min(range.start, e);
+        if k < e - s {
+            Ok(self.quantile(s..e, k))
+        } else {
+            Err(WaveletError::IndexOutOfBounds {
+                index: k,
+                len: e - s,
+            })
+        }
+    }
+
+    /// Returns the lower median of `text[range]`, or `None` if the range is empty.
+    pub fn median(&self, range: Range<u64>) -> Option<T> {
+        let e = cmp::min(range.end, self.len);
+        let s = cmp::min(range.start, e);
+        if s == e {
+            None
+        } else {
+            Some(self.quantile(s..e, (e - s - 1) / 2))
+        }
+    }
+
+    /// Returns the value at fraction `p` of the sorted `text[range]`, i.e. the
+    /// `floor(p * (len - 1))`-th smallest, or `None` if the range is empty.
+    ///
+    /// `p` is clamped to `[0.0, 1.0]`; a NaN is treated as 0.0.
+    pub fn percentile(&self, range: Range<u64>, p: f64) -> Option<T> {
+        let e = cmp::min(range.end, self.len);
+        let s = cmp::min(range.start, e);
+        if s == e {
+            return None;
+        }
+        let k = (p.clamp(0.0, 1.0) * (e - s - 1) as f64) as u64;
+        Some(self.quantile(s..e, cmp::min(k, e - s - 1)))
+    }
+
+    /// Returns the (k+1)-th largest value in `text[range]`, or `None` if `k` is
+    /// not less than the length of the range.
+    pub fn range_kth_largest(&self, range: Range<u64>, k: u64) -> Option<T> {
+        let e = cmp::min(range.end, self.len);
+        let s = cmp::min(range.start, e);
+        if k < e - s {
+            Some(self.quantile(s..e, e - s - 1 - k))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the (k+1)-th smallest value of the whole text, or `None` if
+    /// `k >= len()`.
+    pub fn kth_smallest(&self, k: u64) -> Option<T> {
+        self.try_quantile(0..self.len, k).ok()
+    }
+
+    /// Returns the (k+1)-th largest value of the whole text, or `None` if
+    /// `k >= len()`.
+    pub fn kth_largest(&self, k: u64) -> Option<T> {
+        self.range_kth_largest(0..self.len, k)
+    }
+
+    /// Returns the smallest value in `text[range]`, or `None` if the range is empty.
+    pub fn range_min(&self, range: Range<u64>) -> Option<T> {
+        self.range_extreme(range, false)
+    }
+
+    /// Returns the largest value in `text[range]`, or `None` if the range is empty.
+    pub fn range_max(&self, range: Range<u64>) -> Option<T> {
+        self.range_extreme(range, true)
+    }
+
+    /// Counts the positions in `w` whose value lies strictly between the values
+    /// at positions `i` and `j`, in either order.
+    ///
+    /// `i` and `j` must be less than `len()`.
+    pub fn count_between_values_at(&self, w: Range<u64>, i: u64, j: u64) -> u64 {
+        let a = self.access(i);
+        let b = self.access(j);
+        let (lo, hi) = if a.into() <= b.into() { (a, b) } else { (b, a) };
+        if lo.into() == hi.into() {
+            return 0;
+        }
+        self.range_freq(w, lo + T::one()..hi)
+    }
+
+    /// Counts the occurrences of the smallest value of `text[range]` within it,
+    /// or returns 0 if the range is empty.
+    pub fn count_of_min(&self, range: Range<u64>) -> u64 {
+        self.range_min(range.clone())
+            .map_or(0, |c| self.count(c, range))
+    }
+
+    /// Counts the occurrences of the largest value of `text[range]` within it,
+    /// or returns 0 if the range is empty.
+    pub fn count_of_max(&self, range: Range<u64>) -> u64 {
+        self.range_max(range.clone())
+            .map_or(0, |c| self.count(c, range))
+    }
+
+    // Descends preferring the `prefer` branch whenever it is nonempty.
+    fn range_extreme(&self, range: Range<u64>, prefer: bool) -> Option<T> {
+        let mut e = cmp::min(range.end, self.len);
+        let mut s = cmp::min(range.start, e);
+        if s == e {
+            return None;
+        }
+        let mut n = T::zero();
+        for (r, bv) in self.rows.iter().enumerate() {
+            let ps = bv.rank(prefer, s);
+            let pe = bv.rank(prefer, e);
+            let b = if ps < pe { prefer } else { !prefer };
+            s = bv.rank(b, s);
+            e = bv.rank(b, e);
+            if b {
+                let z = self.partitions[r];
+                s += z;
+                e += z;
+                n = n | (T::one() << (self.size - (r as u64) - 1));
+            }
+        }
+        Some(n)
+    }
+
+    /// Counts the values less than `c` in `text[0..k)`.
+    pub fn rank_less_than(&self, c: T, k: u64) -> u64 {
+        self.count_relative(c, 0..k).0
+    }
+
+    /// Alias of `rank_less_than`.
+    pub fn rank_lt(&self, c: T, k: u64) -> u64 {
+        self.rank_less_than(c, k)
+    }
+
+    /// Counts the values less than or equal to `c` in `text[0..k)`.
+    pub fn rank_le(&self, c: T, k: u64) -> u64 {
+        let (less, equal) = self.count_relative(c, 0..k);
+        less + equal
+    }
+
+    /// Counts the values greater than `c` in `text[0..k)`.
+    pub fn rank_gt(&self, c: T, k: u64) -> u64 {
+        let (less, equal) = self.count_relative(c, 0..k);
+        cmp::min(k, self.len) - less - equal
+    }
+
+    /// Counts the positions in `pos` whose value lies in `[val.start, val.end)`.
+    pub fn range_freq(&self, pos: Range<u64>, val: Range<T>) -> u64 {
+        if val.start.into() >= val.end.into() {
+            return 0;
+        }
+        self.count_relative(val.end, pos.clone()).0 - self.count_relative(val.start, pos).0
+    }
+
+    /// Counts the positions in `pos` whose value lies in `[lo, hi]`.
+    ///
+    /// Unlike `range_freq(pos, lo..hi + 1)`, this works for `hi` equal to the
+    /// largest value of `T`.
+    pub fn range_freq_inclusive(&self, pos: Range<u64>, lo: T, hi: T) -> u64 {
+        if lo.into() > hi.into() {
+            return 0;
+        }
+        let (less, equal) = self.count_relative(hi, pos.clone());
+        less + equal - self.count_relative(lo, pos).0
+    }
+
+    /// Returns up to `k` most frequent values in `text[range]` with their
+    /// counts, sorted by descending count and then by ascending value.
+    pub fn top_k(&self, range: Range<u64>, k: usize) -> Vec<(T, u64)> {
+        self.top_k_within(range, 0, u64::MAX, k)
+    }
+
+    /// Same as `top_k`, but only considers the values in `[val.start, val.end)`.
+    pub fn top_k_in_value_range(&self, pos: Range<u64>, val: Range<T>, k: usize) -> Vec<(T, u64)> {
+        let (lo, hi) = (val.start.into(), val.end.into());
+        if lo >= hi {
+            return Vec::new();
+        }
+        self.top_k_within(pos, lo, hi - 1, k)
+    }
+
+    // Pops nodes by interval width, skipping those whose values all lie outside
+    // `[lo, last]`. A width bounds the count of any value below the node, so the
+    // leaves still come out in order of their counts.
+    fn top_k_within(&self, range: Range<u64>, lo: u64, last: u64, k: usize) -> Vec<(T, u64)> {
+        let e = cmp::min(range.end, self.len);
+        let s = cmp::min(range.start, e);
+        let overlaps = |n: u64, r: usize| {
+            let levels = self.size - r as u64;
+            let max = if levels >= 64 {
+                u64::MAX
+            } else {
+                n | ((1 << levels) - 1)
+            };
+            n <= last && max >= lo
+        };
+        let mut result = Vec::new();
+        // Nodes are ordered by interval width, then by the smallest value they cover.
+        let mut heap = BinaryHeap::new();
+        if s < e && overlaps(0, 0) {
+            heap.push((e - s, Reverse(0u64), 0usize, s));
+        }
+        while result.len() < k {
+            let (w, Reverse(n), r, s) = match heap.pop() {
+                Some(node) => node,
+                None => break,
+            };
+            if r == self.rows.len() {
+                result.push((self.value_of(n), w));
+                continue;
+            }
+            let bv = &self.rows[r];
+            let e = s + w;
+            let zs = bv.rank0(s);
+            let ze = bv.rank0(e);
+            if ze > zs && overlaps(n, r + 1) {
+                heap.push((ze - zs, Reverse(n), r + 1, zs));
+            }
+            let z = self.partitions[r];
+            let os = z + (s - zs);
+            let oe = z + (e - ze);
+            let n = n | (1 << (self.size - (r as u64) - 1));
+            if oe > os && overlaps(n, r + 1) {
+                heap.push((oe - os, Reverse(n), r + 1, os));
+            }
+        }
+        result
+    }
+
+    /// Counts the positions whose value lies in `val_a` in `self` and in
+    /// `val_b` in `other`, which must have the same length.
+    ///
+    /// The values of `self` are only descended into where they can lie in
+    /// `val_a`. Every position matching `val_a` is then located with `select`
+    /// and looked up in `other`, so this takes `O(m · size)` rank and select
+    /// operations for `m` matching positions in `self`.
+    pub fn co_range_count(
+        &self,
+        other: &WaveletMatrix<T, B>,
+        val_a: Range<T>,
+        val_b: Range<T>,
+    ) -> u64 {
+        assert_eq!(
+            self.len, other.len,
+            "co_range_count: matrices must have the same length"
+        );
+        let (lo_a, hi_a) = (val_a.start.into(), val_a.end.into());
+        let (lo_b, hi_b) = (val_b.start.into(), val_b.end.into());
+        let mut count = 0;
+        self.for_each_leaf_in(0, 0, self.len, 0, lo_a..hi_a, &mut |n, s, e| {
+            let c = self.value_of(n);
+            for k in 0..(e - s) {
+                let b = other.access(self.select(c, k)).into();
+                if lo_b <= b && b < hi_b {
+                    count += 1;
+                }
+            }
+        });
+        count
+    }
+
+    /// Returns the largest value less than `x` in `text[range]`.
+    pub fn prev_value(&self, range: Range<u64>, x: T) -> Option<T> {
+        let e = cmp::min(range.end, self.len);
+        let s = cmp::min(range.start, e);
+        self.prev_value_from(0, s, e, 0, x.into())
+            .map(|n| self.value_of(n))
+    }
+
+    /// Returns the smallest value greater than `x` in `text[range]`.
+    pub fn next_value(&self, range: Range<u64>, x: T) -> Option<T> {
+        let e = cmp::min(range.end, self.len);
+        let s = cmp::min(range.start, e);
+        self.next_value_from(0, s, e, 0, x.into())
+            .map(|n| self.value_of(n))
+    }
+
+    /// Returns the distinct values that appear in both `text[a]` and
+    /// `text[b]`, in ascending order.
+    pub fn intersect(&self, a: Range<u64>, b: Range<u64>) -> Vec<T> {
+        let ae = cmp::min(a.end, self.len);
+        let be = cmp::min(b.end, self.len);
+        let a = cmp::min(a.start, ae)..ae;
+        let b = cmp::min(b.start, be)..be;
+        let mut result = Vec::new();
+        self.intersect_from(0, a, b, 0, &mut result);
+        result
+    }
+
+    fn intersect_from(&self, r: usize, a: Range<u64>, b: Range<u64>, n: u64, result: &mut Vec<T>) {
+        if a.start == a.end || b.start == b.end {
+            return;
+        }
+        if r == self.rows.len() {
+            result.push(self.value_of(n));
+            return;
+        }
+        let bv = &self.rows[r];
+        let (azs, aze) = (bv.rank0(a.start), bv.rank0(a.end));
+        let (bzs, bze) = (bv.rank0(b.start), bv.rank0(b.end));
+        self.intersect_from(r + 1, azs..aze, bzs..bze, n, result);
+        let z = self.partitions[r];
+        let bit = 1 << (self.size - (r as u64) - 1);
+        self.intersect_from(
+            r + 1,
+            (z + bv.rank1(a.start))..(z + bv.rank1(a.end)),
+            (z + bv.rank1(b.start))..(z + bv.rank1(b.end)),
+            n | bit,
+            result,
+        );
+    }
+
+    /// Counts the distinct values in `text[range]`.
+    pub fn count_distinct(&self, range: Range<u64>) -> u64 {
+        let e = cmp::min(range.end, self.len);
+        let s = cmp::min(range.start, e);
+        let mut count = 0;
+        self.for_each_leaf(0, s, e, 0, &mut |_, _, _| count += 1);
+        count
+    }
+
+    /// Returns every distinct value in `text[range]` with its number of
+    /// occurrences, in ascending order of value.
+    pub fn range_list(&self, range: Range<u64>) -> Vec<(T, u64)> {
+        let e = cmp::min(range.end, self.len);
+        let s = cmp::min(range.start, e);
+        let mut list = Vec::new();
+        self.for_each_leaf(0, s, e, 0, &mut |n, s, e| {
+            list.push((self.value_of(n), e - s))
+        });
+        list
+    }
+
+    /// Lazily yields every distinct value in `text[range]` with its number of
+    /// occurrences, in ascending order of value.
+    pub fn distinct_iter(&self, range: Range<u64>) -> impl Iterator<Item = (T, u64)> + '_ {
+        let e = cmp::min(range.end, self.len);
+        let s = cmp::min(range.start, e);
+        // Nodes still to visit as `(level, start, end, bits so far)`. The zero
+        // child is pushed last so that it is popped first.
+        let mut stack = vec![(0, s, e, 0u64)];
+        core::iter::from_fn(move || {
+            while let Some((r, s, e, n)) = stack.pop() {
+                if s == e {
+                    continue;
+                }
+                if r == self.rows.len() {
+                    return Some((self.value_of(n), e - s));
+                }
+                let bv = &self.rows[r];
+                let zs = bv.rank0(s);
+                let ze = bv.rank0(e);
+                let z = self.partitions[r];
+                let bit = 1 << (self.size - (r as u64) - 1);
+                stack.push((r + 1, z + (s - zs), z + (e - ze), n | bit));
+                stack.push((r + 1, zs, ze, n));
+            }
+            None
+        })
+    }
+
+    /// Returns the elements of `text[range]` sorted by value, each paired with
+    /// its position. Equal values are ordered by position.
+    ///
+    /// Each distinct value is found by one pruned traversal, and each of the
+    /// `m` elements then costs a `select`, for `O(m · size)` rank and select
+    /// operations in total.
+    pub fn sorted_with_positions(&self, range: Range<u64>) -> Vec<(T, u64)> {
+        let e = cmp::min(range.end, self.len);
+        let s = cmp::min(range.start, e);
+        let mut sorted = Vec::with_capacity((e - s) as usize);
+        self.for_each_leaf(0, s, e, 0, &mut |n, ls, le| {
+            let c = self.value_of(n);
+            let first = self.rank(c, s);
+            for k in first..first + (le - ls) {
+                sorted.push((c, self.select(c, k)));
+            }
+        });
+        sorted
+    }
+
+    /// Returns every distinct value of the whole text with its number of
+    /// occurrences, in ascending order of value.
+    pub fn histogram(&self) -> Vec<(T, u64)> {
+        self.range_list(0..self.len)
+    }
+
+    /// Returns every distinct value of the whole text with its number of
+    /// occurrences, by descending count and then ascending value.
+    pub fn frequencies_desc(&self) -> Vec<(T, u64)> {
+        let mut freqs = self.histogram();
+        // `histogram` is ordered by value, which the stable sort keeps for ties.
+        freqs.sort_by_key(|&(_, count)| Reverse(count));
+        freqs
+    }
+
+    /// Returns the zeroth-order empirical entropy of the text in bits per
+    /// symbol, or 0.0 if the text is empty.
+    #[cfg(feature = "std")]
+    pub fn zeroth_order_entropy(&self) -> f64 {
+        let n = self.len as f64;
+        self.histogram()
+            .iter()
+            .map(|&(_, c)| {
+                let p = c as f64 / n;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    /// Returns the sum of the values in `text[range]`.
+    ///
+    /// The descent visits each distinct value of the range once and adds it
+    /// times its count, so the cost grows with the number of distinct values
+    /// in the range (times the bit width) rather than with its length.
+    ///
+    /// Panics if the sum does not fit in a `u64`; the arithmetic is checked in
+    /// release builds too, so an overflow never wraps silently.
+    pub fn sum_in_range(&self, range: Range<u64>) -> u64 {
+        let e = cmp::min(range.end, self.len);
+        let s = cmp::min(range.start, e);
+        let mut sum = Some(0u64);
+        self.for_each_leaf(0, s, e, 0, &mut |n, s, e| {
+            sum = sum.and_then(|t| n.checked_mul(e - s).and_then(|v| t.checked_add(v)));
+        });
+        sum.expect("the sum of the range overflows u64")
+    }
+
+    // Calls `f(n, s, e)` for every leaf below the node at level `r` whose
+    // interval `[s, e)` is nonempty, in ascending order of the value `n`.
+    fn for_each_leaf<F: FnMut(u64, u64, u64)>(&self, r: usize, s: u64, e: u64, n: u64, f: &mut F) {
+        if s == e {
+            return;
+        }
+        if r == self.rows.len() {
+            f(n, s, e);
+            return;
+        }
+        let bv = &self.rows[r];
+        let zs = bv.rank0(s);
+        let ze = bv.rank0(e);
+        self.for_each_leaf(r + 1, zs, ze, n, f);
+        let z = self.partitions[r];
+        let bit = 1 << (self.size - (r as u64) - 1);
+        self.for_each_leaf(r + 1, z + (s - zs), z + (e - ze), n | bit, f);
+    }
+
+    // Same as `for_each_leaf`, but skips the nodes whose values all lie
+    // outside `val`.
+    fn for_each_leaf_in<F: FnMut(u64, u64, u64)>(
+        &self,
+        r: usize,
+        s: u64,
+        e: u64,
+        n: u64,
+        val: Range<u64>,
+        f: &mut F,
+    ) {
+        let rest = self.size - (r as u64);
+        let max = n | 1u64.checked_shl(rest as u32).map_or(u64::MAX, |m| m - 1);
+        if s == e || max < val.start || n >= val.end {
+            return;
+        }
+        if r == self.rows.len() {
+            f(n, s, e);
+            return;
+        }
+        let bv = &self.rows[r];
+        let zs = bv.rank0(s);
+        let ze = bv.rank0(e);
+        self.for_each_leaf_in(r + 1, zs, ze, n, val.clone(), f);
+        let z = self.partitions[r];
+        let bit = 1 << (rest - 1);
+        self.for_each_leaf_in(r + 1, z + (s - zs), z + (e - ze), n | bit, val, f);
+    }
+
+    // `n` holds the bits chosen above level `r`, i.e. the smallest value of the node.
+    fn prev_value_from(&self, r: usize, s: u64, e: u64, n: u64, x: u64) -> Option<u64> {
+        if s == e || n >= x {
+            return None;
+        }
+        if r == self.rows.len() {
+            return Some(n);
+        }
+        let bv = &self.rows[r];
+        let zs = bv.rank0(s);
+        let ze = bv.rank0(e);
+        let z = self.partitions[r];
+        let bit = 1 << (self.size - (r as u64) - 1);
+        self.prev_value_from(r + 1, z + (s - zs), z + (e - ze), n | bit, x)
+            .or_else(|| self.prev_value_from(r + 1, zs, ze, n, x))
+    }
+
+    fn next_value_from(&self, r: usize, s: u64, e: u64, n: u64, x: u64) -> Option<u64> {
+        let rest = self.size - (r as u64);
+        let max = n | 1u64.checked_shl(rest as u32).map_or(u64::MAX, |m| m - 1);
+        if s == e || max <= x {
+            return None;
+        }
+        if r == self.rows.len() {
+            return Some(n);
+        }
+        let bv = &self.rows[r];
+        let zs = bv.rank0(s);
+        let ze = bv.rank0(e);
+        let z = self.partitions[r];
+        let bit = 1 << (rest - 1);
+        self.next_value_from(r + 1, zs, ze, n, x)
+            .or_else(|| self.next_value_from(r + 1, z + (s - zs), z + (e - ze), n | bit, x))
+    }
+
+    /// Returns the most frequent value in `text[range]` and its count, or
+    /// `None` if the range is empty. Ties are broken by the smaller value.
+    ///
+    /// This is the `top_k` traversal stopped at the first leaf: every node left
+    /// in the heap is at most as wide as that leaf, so none can beat it.
+    pub fn mode(&self, range: Range<u64>) -> Option<(T, u64)> {
+        self.top_k(range, 1).pop()
+    }
+
+    /// Same as `mode`, but returns up to `limit` positions of the most frequent
+    /// value within `text[range]`, in ascending order, instead of its count.
+    pub fn mode_with_positions(&self, range: Range<u64>, limit: usize) -> Option<(T, Vec<u64>)> {
+        let (c, count) = self.mode(range.clone())?;
+        let first = self.rank(c, range.start);
+        let last = first + cmp::min(count, limit as u64);
+        Some((c, (first..last).map(|k| self.select(c, k)).collect()))
+    }
+
+    fn value_of(&self, n: u64) -> T {
+        from_bits(n, self.size)
+    }
+
+    /// Counts the values in `text[range]` that are less than `c` and equal to `c`.
+    fn count_relative(&self, c: T, range: Range<u64>) -> (u64, u64) {
+        let n = c.into();
+        let mut e = cmp::min(range.end, self.len);
+        let mut s = cmp::min(range.start, e);
+        if !fits_in(n, self.size) {
+            return (e - s, 0);
+        }
+        let mut less = 0u64;
+        for (r, bv) in self.rows.iter().enumerate() {
+            let b = (n >> (self.size - (r as u64) - 1)) & 1 > 0;
+            if b {
+                less += bv.rank0(e) - bv.rank0(s);
+                let z = self.partitions[r];
+                s = z + bv.rank1(s);
+                e = z + bv.rank1(e);
+            } else {
+                s = bv.rank0(s);
+                e = bv.rank0(e);
+            }
+        }
+        (less, e - s)
+    }
+
+    /// Writes the matrix in a compact binary format.
+    ///
+    /// The stream starts with the magic bytes `WVMX`, a format version,
+    /// `size`, `len` and the partitions, followed by the bits of each row
+    /// packed LSB-first into 64-bit words. All integers are little endian.
+    #[cfg(feature = "std")]
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        w.write_all(&self.size.to_le_bytes())?;
+        w.write_all(&self.len.to_le_bytes())?;
+        for &p in &self.partitions {
+            w.write_all(&p.to_le_bytes())?;
+        }
+        for bv in &self.rows {
+            let mut word = 0u64;
+            for i in 0..self.len {
+                if bv.get(i) {
+                    word |= 1 << (i % 64);
+                }
+                if i % 64 == 63 {
+                    w.write_all(&word.to_le_bytes())?;
+                    word = 0;
+                }
+            }
+            if self.len % 64 != 0 {
+                w.write_all(&word.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of bits per symbol, i.e. the number of rows.
+    pub fn bit_width(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the number of bit rows, which equals `bit_width()`.
+    pub fn num_rows(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Returns the bit row at level `r`, where level 0 holds the most
+    /// significant bit.
+    ///
+    /// Row `r` lists the elements in the order left by the stable partitions
+    /// of the levels above it. Once the bit is taken off, its zeros go before its
+    /// ones, so the first `rank0(len)` positions of row `r + 1` come from zeros
+    /// at level `r`. Panics if `r >= num_rows()`.
+    pub fn row(&self, r: usize) -> &B {
+        &self.rows[r]
+    }
+
+    /// Returns the bits of row `r` packed into 64-bit words.
+    ///
+    /// Bit `i` of the row is bit `i % 64` of word `i / 64`, counting from the
+    /// least significant bit. The unused high bits of the last word are zero,
+    /// and an empty row yields no words. Panics if `r >= num_rows()`.
+    pub fn row_as_u64s(&self, r: usize) -> Vec<u64> {
+        let bv = &self.rows[r];
+        let mut words = vec![0u64; self.len.div_ceil(64) as usize];
+        for i in 0..self.len {
+            if bv.get(i) {
+                words[(i / 64) as usize] |= 1 << (i % 64);
+            }
+        }
+        words
+    }
+
+    /// Returns a Graphviz `digraph` of the partitioning: one node per nonempty
+    /// interval `[s, e)` of each level, with edges labeled by the bit that
+    /// leads to the child. Leaves also show their value.
+    ///
+    /// Level `r` has at most `min(2^r, len())` nodes, so this is meant for
+    /// small matrices.
+    pub fn to_dot(&self) -> String {
+        use core::fmt::Write;
+        let mut dot = String::from("digraph WaveletMatrix {\n  node [shape=box];\n");
+        let mut nodes = vec![(0u64, 0, self.len)];
+        for r in 0..=self.rows.len() {
+            let mut next = Vec::new();
+            for &(n, s, e) in &nodes {
+                let id = format!("n{}_{}", r, n);
+                if r == self.rows.len() {
+                    let _ = writeln!(dot, "  {} [label=\"[{}, {}) = {}\"];", id, s, e, n);
+                    continue;
+                }
+                let _ = writeln!(dot, "  {} [label=\"[{}, {})\"];", id, s, e);
+                let bv = &self.rows[r];
+                let (zs, ze) = (bv.rank0(s), bv.rank0(e));
+                let z = self.partitions[r];
+                let bit = 1 << (self.size - (r as u64) - 1);
+                for (b, cn, cs, ce) in [(0, n, zs, ze), (1, n | bit, z + s - zs, z + e - ze)] {
+                    if cs < ce {
+                        let _ = writeln!(dot, "  {} -> n{}_{} [label=\"{}\"];", id, r + 1, cn, b);
+                        next.push((cn, cs, ce));
+                    }
+                }
+            }
+            nodes = next;
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Returns the number of ones in each row, from level 0 down.
+    pub fn row_popcounts(&self) -> Vec<u64> {
+        self.rows
+            .iter()
+            .zip(&self.partitions)
+            .map(|(bv, &z)| {
+                let ones = bv.len() - z;
+                debug_assert_eq!(ones, bv.rank1(bv.len()));
+                ones
+            })
+            .collect()
+    }
+
+    /// Counts the occurrences of `bit` in the first `pos` positions of the row
+    /// at `level`.
+    ///
+    /// Panics if `level >= num_rows()` or `pos > len()`.
+    pub fn level_rank(&self, level: usize, bit: bool, pos: u64) -> u64 {
+        assert!(
+            level < self.rows.len(),
+            "level_rank: level ({}) must be less than the number of rows ({})",
+            level,
+            self.rows.len()
+        );
+        assert!(
+            pos <= self.len,
+            "level_rank: pos ({}) must not exceed the length ({})",
+            pos,
+            self.len
+        );
+        self.rows[level].rank(bit, pos)
+    }
+
+    /// Returns the number of zeros in the row at `level`, i.e. where the
+    /// elements taking the one-branch start in the next level.
+    ///
+    /// Panics if `level >= num_rows()`.
+    pub fn level_partition(&self, level: usize) -> u64 {
+        self.partitions[level]
+    }
+
+    /// Returns the number of representable symbols, `2^bit_width()`,
+    /// saturating at `u64::MAX`.
+    pub fn alphabet_size(&self) -> u64 {
+        if self.size >= 64 {
+            u64::MAX
+        } else {
+            1 << self.size
+        }
+    }
+}
+
+impl<T, B: FID> WaveletMatrix<T, B> {
+    /// Checks the structural invariants of the matrix: there is one row and
+    /// one partition per bit of width, every row has `len()` bits, and each
+    /// partition is the number of zeros in its row.
+    ///
+    /// Useful after loading a matrix from untrusted data. The error describes
+    /// the first violation found.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.rows.len() as u64 != self.size {
+            return Err(format!(
+                "{} rows for a bit width of {}",
+                self.rows.len(),
+                self.size
+            ));
+        }
+        if self.partitions.len() as u64 != self.size {
+            return Err(format!(
+                "{} partitions for a bit width of {}",
+                self.partitions.len(),
+                self.size
+            ));
+        }
+        for (r, bv) in self.rows.iter().enumerate() {
+            if bv.len() != self.len {
+                return Err(format!(
+                    "row {} has {} bits but the length is {}",
+                    r,
+                    bv.len(),
+                    self.len
+                ));
+            }
+            let zeros = bv.rank0(self.len);
+            if self.partitions[r] != zeros {
+                return Err(format!(
+                    "partition {} is {} but row {} has {} zeros",
+                    r, self.partitions[r], r, zeros
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T, B: HeapSize> WaveletMatrix<T, B> {
+    /// Returns the number of bytes used by the bit rows and partitions.
+    ///
+    /// Rows sharing one allocation, as built by `new_dedup`, are counted once.
+    pub fn heap_size(&self) -> usize {
+        self.rows
+            .iter()
+            .enumerate()
+            .filter(|&(r, bv)| !self.rows[..r].iter().any(|prev| prev.shares_allocation(bv)))
+            .map(|(_, bv)| bv.heap_size())
+            .sum::<usize>()
+            + self.partitions.len() * core::mem::size_of::<u64>()
+    }
+}
+
+impl<T> From<Vec<T>> for WaveletMatrix<T>
+where
+    T: Into<u64> + Copy + Clone + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
+{
+    fn from(text: Vec<T>) -> Self {
+        Self::new(text)
+    }
+}
+
+impl<'a, T> From<&'a [T]> for WaveletMatrix<T>
+where
+    T: Into<u64> + Copy + Clone + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
+{
+    fn from(text: &'a [T]) -> Self {
+        Self::new(text)
+    }
+}
+
+/// An empty matrix with the full bit width of `T`, as built by `new(&[])`.
+impl<T, B> Default for WaveletMatrix<T, B>
+where
+    T: Into<u64> + Copy + Clone + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
+    B: FID + Default + PushBit,
+{
+    fn default() -> Self {
+        Self::new_with_backend([], core::mem::size_of::<T>() as u64 * 8)
+    }
+}
+
+/// Builds a matrix from an iterator of values.
+///
+/// Construction needs the whole sequence, so the values are collected into a
+/// temporary `Vec<T>` first; peak memory is one `T` per element on top of the
+/// matrix itself.
+impl<T> core::iter::FromIterator<T> for WaveletMatrix<T>
+where
+    T: Into<u64> + Copy + Clone + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
+{
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self::new(iter.into_iter().collect::<Vec<T>>())
+    }
+}
+
+// The `InvalidFormat` reason for data that fails `validate`.
+#[cfg(any(feature = "std", feature = "serde"))]
+const INCONSISTENT: &str = "rows and partitions are inconsistent";
+
+// Checks that serialized data with `size` bits per symbol can be read as `T`.
+#[cfg(any(feature = "std", feature = "serde"))]
+fn check_size<T>(size: u64) -> Result<(), WaveletError> {
+    if size > 64 {
+        return Err(WaveletError::InvalidFormat("size exceeds 64 bits"));
+    }
+    if size > core::mem::size_of::<T>() as u64 * 8 {
+        return Err(WaveletError::InvalidFormat(
+            "size exceeds the width of the symbol type",
+        ));
+    }
+    Ok(())
+}
+
+// The fields of a deserialized matrix, before they are checked like the
+// input of `read_from`.
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct RawWaveletMatrix<B> {
+    rows: Vec<B>,
+    size: u64,
+    len: u64,
+    partitions: Vec<u64>,
+}
+
+#[cfg(feature = "serde")]
+impl<T, B: FID> TryFrom<RawWaveletMatrix<B>> for WaveletMatrix<T, B> {
+    type Error = WaveletError;
+
+    fn try_from(raw: RawWaveletMatrix<B>) -> Result<Self, WaveletError> {
+        check_size::<T>(raw.size)?;
+        let wm = WaveletMatrix {
+            rows: raw.rows,
+            size: raw.size,
+            len: raw.len,
+            partitions: raw.partitions,
+            _t: core::marker::PhantomData,
+        };
+        wm.validate()
+            .map_err(|_| WaveletError::InvalidFormat(INCONSISTENT))?;
+        Ok(wm)
+    }
+}
+
+// Whether `n` has no bits set at or above `size`.
+fn fits_in(n: u64, size: u64) -> bool {
+    size >= 64 || n >> size == 0
+}
+
+// Whether `a` and `b` hold the same bits.
+fn same_bits<B: FID>(a: &B, b: &B) -> bool {
+    a.len() == b.len()
+        && a.rank1(a.len()) == b.rank1(b.len())
+        && (0..a.len()).all(|i| a.get(i) == b.get(i))
+}
+
+// Builds a `T` from the low `size` bits of `n`.
+fn from_bits<T>(n: u64, size: u64) -> T
+where
+    T: Copy + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
+{
+    let mut v = T::zero();
+    for i in 0..size {
+        if (n >> i) & 1 > 0 {
+            v = v | (T::one() << i);
+        }
+    }
+    v
+}
+
+/// The descent path of one symbol, created by `WaveletMatrix::prepared_rank`.
+///
+/// Holds the bit and partition offset of each level, and where the symbol's
+/// interval starts at the bottom, so `rank` only follows the end position.
+pub struct PreparedRank<'a, B = BitVector> {
+    rows: &'a [B],
+    steps: Vec<(bool, u64)>,
+    start: u64,
+    len: u64,
+    // Whether the symbol fits in the bit width; if not, it never occurs.
+    fits: bool,
+}
+
+impl<'a, B: FID> PreparedRank<'a, B> {
+    /// Counts the occurrences of the symbol in `text[0..k)`.
+    pub fn rank(&self, k: u64) -> u64 {
+        if !self.fits {
+            return 0;
+        }
+        let mut e = cmp::min(k, self.len);
+        for (bv, &(b, offset)) in self.rows.iter().zip(&self.steps) {
+            e = bv.rank(b, e) + offset;
+        }
+        e - self.start
+    }
+}
+
+/// An iterator over the values of a `WaveletMatrix`, created by `iter`.
+pub struct Iter<'a, T, B = BitVector> {
+    wm: &'a WaveletMatrix<T, B>,
+    pos: u64,
+    end: u64,
+    buf: vec::IntoIter<T>,
+}
+
+impl<'a, T, B> Iterator for Iter<'a, T, B>
+where
+    T: Into<u64> + Copy + Clone + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
+    B: FID,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if let Some(v) = self.buf.next() {
+            return Some(v);
+        }
+        if self.pos >= self.end {
+            return None;
+        }
+        let e = cmp::min(self.pos + ITER_CHUNK, self.end);
+        let mut buf = Vec::with_capacity((e - self.pos) as usize);
+        self.wm.decode_range(self.pos, e, &mut buf);
+        self.pos = e;
+        self.buf = buf.into_iter();
+        self.buf.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.buf.len() + (self.end - self.pos) as usize;
+        (n, Some(n))
+    }
+}
+
+impl<'a, T, B> ExactSizeIterator for Iter<'a, T, B>
+where
+    T: Into<u64> + Copy + Clone + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
+    B: FID,
+{
+}
+
+/// Iterates over the decoded values.
+///
+/// ```
+/// use wavelet_tree::WaveletMatrix;
+///
+/// let wm = WaveletMatrix::new(&[4u8, 7, 6, 5]);
+/// let sum = (&wm).into_iter().map(Into::<u64>::into).sum::<u64>();
+/// assert_eq!(sum, 22);
+///
+/// let mut values = vec![];
+/// for x in &wm {
+///     values.push(x);
+/// }
+/// assert_eq!(values, vec![4, 7, 6, 5]);
+/// ```
+impl<'a, T, B> IntoIterator for &'a WaveletMatrix<T, B>
+where
+    T: Into<u64> + Copy + Clone + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
+    B: FID,
+{
+    type Item = T;
+    type IntoIter = Iter<'a, T, B>;
+
+    fn into_iter(self) -> Iter<'a, T, B> {
+        self.iter()
+    }
+}
+
+#[cfg(feature = "std")]
+fn read_exact<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<()> {
+    r.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => WaveletError::Truncated.into(),
+        _ => e,
+    })
+}
+
+#[cfg(feature = "std")]
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    read_exact(r, &mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+impl<T, B: Clone> Clone for WaveletMatrix<T, B> {
+    fn clone(&self) -> Self {
+        WaveletMatrix {
+            rows: self.rows.clone(),
+            size: self.size,
+            len: self.len,
+            partitions: self.partitions.clone(),
+            _t: core::marker::PhantomData,
+        }
+    }
+}
+
+impl<T, B: FID> PartialEq for WaveletMatrix<T, B> {
+    fn eq(&self, other: &Self) -> bool {
+        self.size == other.size
+            && self.len == other.len
+            && self.partitions == other.partitions
+            && self.rows.len() == other.rows.len()
+            && self
+                .rows
+                .iter()
+                .zip(&other.rows)
+                .all(|(a, b)| a.len() == b.len() && (0..a.len()).all(|i| a.get(i) == b.get(i)))
+    }
+}
+
+impl<T, B: FID> Eq for WaveletMatrix<T, B> {}
+
+impl<T, B> fmt::Display for WaveletMatrix<T, B>
+where
+    T: Into<u64> + Copy + Clone + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
+    T: fmt::Display,
+    B: FID,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "WaveletMatrix(size = {}, len = {}) [",
+            self.size, self.len
+        )?;
+        for (i, v) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", v)?;
+        }
+        write!(f, "]")
+    }
+}
+
+impl<T: fmt::Debug, B: FID> fmt::Debug for WaveletMatrix<T, B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.rows.is_empty() || self.len == 0 {
+            return write!(f, "WaveletMatrix {{ (empty) }}");
+        }
+        writeln!(f, "WaveletMatrix {{")?;
+        for bv in &self.rows {
+            write!(f, "  ")?;
+            for i in 0..self.len {
+                write!(f, "{}", if bv.get(i) { "1" } else { "0" })?;
+            }
+            writeln!(f, "")?;
+        }
+        write!(f, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::{BTreeMap, HashSet};
+
+    #[test]
+    fn rank_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let size = 3;
+        let wm = WaveletMatrix::new_with_size(numbers, size);
+        assert_eq!(wm.len, numbers.len() as u64);
+        for i in 0..(1 << size) {
+            let mut r = 0;
+            for (k, &n) in numbers.iter().enumerate() {
+                assert!(
+                    wm.rank(i as u8, k as u64) == r,
+                    "wm.rank({}, {}) == {}",
+                    i,
+                    k,
+                    r
                 );
                 if n == i {
                     r = r + 1;
@@ -158,38 +2236,2080 @@ mod tests {
     }
 
     #[test]
-    fn access_small() {
+    fn rank_range_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..200).map(|_| rng.gen_range(0..16)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 4);
+        for _ in 0..1000 {
+            let c = rng.gen_range(0..16u8);
+            let i = rng.gen_range(0..=250);
+            let j = rng.gen_range(i..=250);
+            let r = wm.rank(c, j) - wm.rank(c, i);
+            assert!(
+                wm.rank_range(c, i..j) == r,
+                "wm.rank_range({}, {}..{}) == {}",
+                c,
+                i,
+                j,
+                r
+            );
+        }
+    }
+
+    #[test]
+    fn access_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let size = 3;
+        let wm = WaveletMatrix::new_with_size(numbers, size);
+        assert_eq!(wm.len, numbers.len() as u64);
+        for (i, &n) in numbers.iter().enumerate() {
+            assert!(wm.access(i as u64) == n, "wm.access({}) == {}", i, n);
+        }
+    }
+
+    #[test]
+    fn get_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        for (i, &n) in numbers.iter().enumerate() {
+            assert_eq!(wm.get(i as u64), Some(n));
+        }
+        assert_eq!(wm.get(numbers.len() as u64), None);
+        assert_eq!(wm.get(1000), None);
+        assert_eq!(wm.get(u64::MAX), None);
+
+        let empty_vec: Vec<u8> = vec![];
+        let wm = WaveletMatrix::new(&empty_vec);
+        assert_eq!(wm.get(0), None);
+    }
+
+    #[test]
+    fn at_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        for (i, &n) in numbers.iter().enumerate() {
+            assert_eq!(wm.at(i as u64), n);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "the len is 12 but the index is 12")]
+    fn at_out_of_bounds() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        wm.at(12);
+    }
+
+    #[test]
+    fn access_batch_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..300).map(|_| rng.gen()).collect();
+        let wm = WaveletMatrix::new(&numbers);
+        let positions: Vec<u64> = (0..500).map(|_| rng.gen_range(0..300)).collect();
+        let expected: Vec<u16> = positions.iter().map(|&k| wm.access(k)).collect();
+        assert_eq!(wm.access_batch(&positions), expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "index out of bounds")]
+    fn access_batch_out_of_bounds() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        wm.access_batch(&[0, 3, 12]);
+    }
+
+    #[test]
+    fn select_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let size = 3;
+        let wm = WaveletMatrix::new_with_size(numbers, size);
+
+        let mut ans: Vec<Vec<u64>> = vec![vec![]; 1 << size];
+        for (i, &n) in numbers.iter().enumerate() {
+            ans[n as usize].push(i as u64);
+        }
+
+        for (c, a) in ans.iter().enumerate() {
+            for (k, &i) in a.iter().enumerate() {
+                assert!(
+                    wm.select(c as u8, k as u64) == i,
+                    "wm.select({}, {}) == {}",
+                    c,
+                    k,
+                    i
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn try_select_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let size = 3;
+        let wm = WaveletMatrix::new_with_size(numbers, size);
+
+        let mut ans: Vec<Vec<u64>> = vec![vec![]; 1 << size];
+        for (i, &n) in numbers.iter().enumerate() {
+            ans[n as usize].push(i as u64);
+        }
+
+        for (c, a) in ans.iter().enumerate() {
+            for (k, &i) in a.iter().enumerate() {
+                assert_eq!(wm.try_select(c as u8, k as u64), Some(i));
+            }
+            for k in a.len()..(a.len() + 3) {
+                assert!(
+                    wm.try_select(c as u8, k as u64).is_none(),
+                    "wm.try_select({}, {}) == None",
+                    c,
+                    k
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn select_after_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let size = 3;
+        let wm = WaveletMatrix::new_with_size(numbers, size);
+        for c in 0..(1u8 << size) {
+            for p in 0..=(numbers.len() + 2) {
+                let ans: Vec<u64> = (0..numbers.len())
+                    .filter(|&i| i >= p && numbers[i] == c)
+                    .map(|i| i as u64)
+                    .collect();
+                for k in 0..(ans.len() + 2) {
+                    let a = ans.get(k).cloned();
+                    assert!(
+                        wm.select_after(c, p as u64, k as u64) == a,
+                        "wm.select_after({}, {}, {}) == {:?}",
+                        c,
+                        p,
+                        k,
+                        a
+                    );
+                }
+            }
+        }
+        assert_eq!(wm.select_after(1, 0, u64::MAX), None);
+    }
+
+    #[test]
+    fn select_kth_in_range_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        for c in 0..8u8 {
+            for i in 0..=numbers.len() {
+                for j in i..=numbers.len() + 1 {
+                    let ans: Vec<u64> = (i..cmp::min(j, numbers.len()))
+                        .filter(|&p| numbers[p] == c)
+                        .map(|p| p as u64)
+                        .collect();
+                    for k in 0..=ans.len() {
+                        assert!(
+                            wm.select_kth_in_range(c, i as u64..j as u64, k as u64)
+                                == ans.get(k).cloned(),
+                            "wm.select_kth_in_range({}, {}..{}, {}) == {:?}",
+                            c,
+                            i,
+                            j,
+                            k,
+                            ans.get(k)
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn quantile_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let size = 3;
+        let wm = WaveletMatrix::new_with_size(numbers, size);
+        for i in 0..numbers.len() {
+            for j in (i + 1)..=numbers.len() {
+                let mut sorted = numbers[i..j].to_vec();
+                sorted.sort();
+                for (k, &n) in sorted.iter().enumerate() {
+                    assert!(
+                        wm.quantile(i as u64..j as u64, k as u64) == n,
+                        "wm.quantile({}..{}, {}) == {}",
+                        i,
+                        j,
+                        k,
+                        n
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn median_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        for &(i, j) in &[(0, 12), (0, 1), (2, 6), (3, 9), (5, 12), (11, 12)] {
+            let mut sorted = numbers[i..j].to_vec();
+            sorted.sort();
+            let m = sorted[(sorted.len() - 1) / 2];
+            assert!(
+                wm.median(i as u64..j as u64) == Some(m),
+                "wm.median({}..{}) == {}",
+                i,
+                j,
+                m
+            );
+        }
+        assert_eq!(wm.median(4..4), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "must be less than the range length")]
+    fn quantile_out_of_range() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        wm.quantile(2..5, 3);
+    }
+
+    #[test]
+    fn rank_less_than_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let size = 3;
+        let wm = WaveletMatrix::new_with_size(numbers, size);
+        for c in 0..=(1u8 << size) {
+            for k in 0..=numbers.len() {
+                let r = numbers[..k].iter().filter(|&&n| n < c).count() as u64;
+                assert!(
+                    wm.rank_less_than(c, k as u64) == r,
+                    "wm.rank_less_than({}, {}) == {}",
+                    c,
+                    k,
+                    r
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rank_lt_le_gt_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let size = 3;
+        let wm = WaveletMatrix::new_with_size(numbers, size);
+        for c in 0..=255u8 {
+            for k in 0..=(numbers.len() as u64 + 3) {
+                let lt = wm.rank_lt(c, k);
+                let le = wm.rank_le(c, k);
+                let gt = wm.rank_gt(c, k);
+                let eq = if c < (1 << size) { wm.rank(c, k) } else { 0 };
+                let min = cmp::min(k, numbers.len() as u64);
+                assert!(
+                    lt + eq + gt == min,
+                    "wm.rank_lt({0}, {1}) + wm.rank({0}, {1}) + wm.rank_gt({0}, {1}) == {2}",
+                    c,
+                    k,
+                    min
+                );
+                assert_eq!(le, lt + eq);
+                let prefix = &numbers[..min as usize];
+                assert_eq!(lt, prefix.iter().filter(|&&n| n < c).count() as u64);
+                assert_eq!(gt, prefix.iter().filter(|&&n| n > c).count() as u64);
+            }
+        }
+    }
+
+    #[test]
+    fn rank_lt_le_gt_extremes() {
+        let numbers = &[0u8, 255, 128, 0, 255, 1];
+        let wm = WaveletMatrix::new(numbers);
+        let len = numbers.len() as u64;
+        assert_eq!(wm.rank_lt(0, len), 0);
+        assert_eq!(wm.rank_le(0, len), 2);
+        assert_eq!(wm.rank_gt(0, len), 4);
+        assert_eq!(wm.rank_lt(255, len), 4);
+        assert_eq!(wm.rank_le(255, len), len);
+        assert_eq!(wm.rank_gt(255, len), 0);
+        assert_eq!(wm.rank_le(255, 100), len);
+    }
+
+    #[test]
+    fn range_freq_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..200).map(|_| rng.gen_range(0..16)).collect();
+        let size = 4;
+        let wm = WaveletMatrix::new_with_size(&numbers, size);
+        for _ in 0..1000 {
+            let i = rng.gen_range(0..=numbers.len());
+            let j = rng.gen_range(i..=numbers.len());
+            let lo = rng.gen_range(0..=40u8);
+            let hi = rng.gen_range(0..=40u8);
+            let f = numbers[i..j].iter().filter(|&&n| lo <= n && n < hi).count() as u64;
+            assert!(
+                wm.range_freq(i as u64..j as u64, lo..hi) == f,
+                "wm.range_freq({}..{}, {}..{}) == {}",
+                i,
+                j,
+                lo,
+                hi,
+                f
+            );
+        }
+        assert_eq!(wm.range_freq(10..10, 0..16), 0);
+        assert_eq!(wm.range_freq(0..200, 5..5), 0);
+        assert_eq!(wm.range_freq(0..1000, 0..255), numbers.len() as u64);
+    }
+
+    #[test]
+    fn top_k_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..200).map(|_| rng.gen_range(0..8)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 3);
+        for _ in 0..500 {
+            let i = rng.gen_range(0..=numbers.len());
+            let j = rng.gen_range(i..=numbers.len());
+            let k = rng.gen_range(0..10);
+            let mut freq = [0u64; 8];
+            for &n in &numbers[i..j] {
+                freq[n as usize] += 1;
+            }
+            let mut ans: Vec<(u8, u64)> = freq
+                .iter()
+                .enumerate()
+                .filter(|&(_, &f)| f > 0)
+                .map(|(c, &f)| (c as u8, f))
+                .collect();
+            ans.sort_by_key(|&(c, f)| (Reverse(f), c));
+            ans.truncate(k);
+            assert!(
+                wm.top_k(i as u64..j as u64, k) == ans,
+                "wm.top_k({}..{}, {}) == {:?}",
+                i,
+                j,
+                k,
+                ans
+            );
+        }
+    }
+
+    #[test]
+    fn prev_next_value_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        for i in 0..=numbers.len() {
+            for j in i..=numbers.len() {
+                let window = &numbers[i..j];
+                for x in 0..=255u8 {
+                    let prev = window.iter().filter(|&&n| n < x).max().cloned();
+                    let next = window.iter().filter(|&&n| n > x).min().cloned();
+                    assert!(
+                        wm.prev_value(i as u64..j as u64, x) == prev,
+                        "wm.prev_value({}..{}, {}) == {:?}",
+                        i,
+                        j,
+                        x,
+                        prev
+                    );
+                    assert!(
+                        wm.next_value(i as u64..j as u64, x) == next,
+                        "wm.next_value({}..{}, {}) == {:?}",
+                        i,
+                        j,
+                        x,
+                        next
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn prev_next_value_full_width() {
+        let numbers = &[0u8, 255, 128, 3];
+        let wm = WaveletMatrix::new(numbers);
+        assert_eq!(wm.prev_value(0..4, 0), None);
+        assert_eq!(wm.prev_value(0..4, 255), Some(128));
+        assert_eq!(wm.prev_value(0..4, 100), Some(3));
+        assert_eq!(wm.next_value(0..4, 255), None);
+        assert_eq!(wm.next_value(0..4, 0), Some(3));
+        assert_eq!(wm.next_value(0..4, 128), Some(255));
+    }
+
+    #[test]
+    fn intersect_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..200).map(|_| rng.gen_range(0..32)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 5);
+        for _ in 0..500 {
+            let ai = rng.gen_range(0..=numbers.len());
+            let aj = rng.gen_range(ai..=numbers.len());
+            let bi = rng.gen_range(0..=numbers.len());
+            let bj = rng.gen_range(bi..=numbers.len());
+            let a: HashSet<u8> = numbers[ai..aj].iter().cloned().collect();
+            let b: HashSet<u8> = numbers[bi..bj].iter().cloned().collect();
+            let mut ans: Vec<u8> = a.intersection(&b).cloned().collect();
+            ans.sort();
+            assert!(
+                wm.intersect(ai as u64..aj as u64, bi as u64..bj as u64) == ans,
+                "wm.intersect({}..{}, {}..{}) == {:?}",
+                ai,
+                aj,
+                bi,
+                bj,
+                ans
+            );
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let size = 3;
+        let wm = WaveletMatrix::new_with_size(numbers, size);
+        let json = serde_json::to_string(&wm).unwrap();
+        let de: WaveletMatrix<u8> = serde_json::from_str(&json).unwrap();
+        assert_eq!(de.len(), wm.len());
+        for i in 0..numbers.len() as u64 {
+            assert_eq!(de.access(i), wm.access(i));
+        }
+        for c in 0..(1u8 << size) {
+            for k in 0..=numbers.len() as u64 {
+                assert_eq!(de.rank(c, k), wm.rank(c, k));
+            }
+            for k in 0..wm.rank(c, wm.len()) {
+                assert_eq!(de.select(c, k), wm.select(c, k));
+            }
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_rejects_tampered_size() {
+        let wm = WaveletMatrix::new_with_size([4u8, 7, 6, 5, 3, 2, 1, 0], 3);
+        let mut value = serde_json::to_value(&wm).unwrap();
+        value["size"] = 9.into();
+        assert!(serde_json::from_value::<WaveletMatrix<u8>>(value.clone()).is_err());
+        value["size"] = 65.into();
+        assert!(serde_json::from_value::<WaveletMatrix<u64>>(value.clone()).is_err());
+        value["size"] = 2.into();
+        assert!(serde_json::from_value::<WaveletMatrix<u8>>(value).is_err());
+
+        let mut value = serde_json::to_value(&wm).unwrap();
+        value["partitions"][0] = 7.into();
+        assert!(serde_json::from_value::<WaveletMatrix<u8>>(value).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn binary_round_trip() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..1000).map(|_| rng.gen()).collect();
+        let wm = WaveletMatrix::new(&numbers);
+        let mut buf = Vec::new();
+        wm.write_to(&mut buf).unwrap();
+        let de = WaveletMatrix::<u16>::read_from(&mut buf.as_slice()).unwrap();
+        assert_eq!(de.len(), wm.len());
+        for (i, &n) in numbers.iter().enumerate() {
+            assert_eq!(de.access(i as u64), n);
+        }
+        for _ in 0..100 {
+            let c = numbers[rng.gen_range(0..numbers.len())];
+            let k = rng.gen_range(0..=numbers.len() as u64);
+            assert_eq!(de.rank(c, k), wm.rank(c, k));
+            assert_eq!(de.select(c, 0), wm.select(c, 0));
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn binary_truncated() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        let mut buf = Vec::new();
+        wm.write_to(&mut buf).unwrap();
+        for l in 0..buf.len() {
+            assert!(
+                WaveletMatrix::<u8>::read_from(&mut &buf[..l]).is_err(),
+                "read_from(buf[..{}]) is Err",
+                l
+            );
+        }
+        buf[0] = b'X';
+        assert!(WaveletMatrix::<u8>::read_from(&mut buf.as_slice()).is_err());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn binary_wrong_type() {
+        let wm = WaveletMatrix::new([300u16, 7, 65535]);
+        let mut buf = Vec::new();
+        wm.write_to(&mut buf).unwrap();
+        let err = WaveletMatrix::<u8>::read_from(&mut buf.as_slice())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.get_ref()
+                .and_then(|e| e.downcast_ref::<WaveletError>())
+                .cloned(),
+            Some(WaveletError::InvalidFormat(
+                "size exceeds the width of the symbol type"
+            ))
+        );
+        let de = WaveletMatrix::<u32>::read_from(&mut buf.as_slice()).unwrap();
+        assert_eq!(de.to_vec(), vec![300, 7, 65535]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn binary_inconsistent() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        let mut buf = Vec::new();
+        wm.write_to(&mut buf).unwrap();
+        // The first partition follows the magic, version, size and length.
+        buf[24] += 1;
+        let err = WaveletMatrix::<u8>::read_from(&mut buf.as_slice())
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.get_ref()
+                .and_then(|e| e.downcast_ref::<WaveletError>())
+                .cloned(),
+            Some(WaveletError::InvalidFormat(
+                "rows and partitions are inconsistent"
+            ))
+        );
+    }
+
+    #[test]
+    fn clone_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        let cloned = wm.clone();
+        for (i, &n) in numbers.iter().enumerate() {
+            assert_eq!(wm.access(i as u64), n);
+            assert_eq!(cloned.access(i as u64), n);
+        }
+    }
+
+    #[test]
+    fn eq_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        assert_eq!(wm, wm.clone());
+        assert_eq!(wm, WaveletMatrix::new_with_size(numbers, 3));
+
+        let mut permuted = numbers.to_vec();
+        permuted.reverse();
+        assert_ne!(wm, WaveletMatrix::new_with_size(&permuted, 3));
+        assert_ne!(wm, WaveletMatrix::new_with_size(numbers, 4));
+    }
+
+    #[test]
+    fn metadata() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        assert!(!wm.is_empty());
+        assert_eq!(wm.bit_width(), 3);
+        assert_eq!(wm.alphabet_size(), 8);
+
+        let wm = WaveletMatrix::new(numbers);
+        assert_eq!(wm.bit_width(), 8);
+        assert_eq!(wm.alphabet_size(), 256);
+
+        let wm = WaveletMatrix::new([1u64, u64::MAX]);
+        assert_eq!(wm.bit_width(), 64);
+        assert_eq!(wm.alphabet_size(), u64::MAX);
+
+        assert!(WaveletMatrix::<u8>::new([]).is_empty());
+    }
+
+    #[test]
+    fn iter_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        assert_eq!(wm.iter().len(), numbers.len());
+        assert_eq!(wm.iter().collect::<Vec<_>>(), numbers.to_vec());
+
+        let wm = WaveletMatrix::new(numbers);
+        assert_eq!(wm.iter().collect::<Vec<_>>(), numbers.to_vec());
+
+        assert_eq!(WaveletMatrix::<u8>::new([]).iter().next(), None);
+    }
+
+    #[test]
+    fn iter_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..3000).map(|_| rng.gen()).collect();
+        let wm = WaveletMatrix::new(&numbers);
+        assert_eq!(wm.iter().collect::<Vec<_>>(), numbers);
+    }
+
+    #[test]
+    fn from_vec_and_slice() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm: WaveletMatrix<u8> = numbers.to_vec().into();
+        assert_eq!(wm.len(), numbers.len() as u64);
+        assert_eq!(wm, WaveletMatrix::new(numbers));
+
+        let wm: WaveletMatrix<u8> = numbers[..5].into();
+        assert_eq!(wm.len(), 5);
+        assert_eq!(wm.iter().collect::<Vec<_>>(), numbers[..5].to_vec());
+    }
+
+    #[test]
+    fn from_iter_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..500).map(|_| rng.gen()).collect();
+        let wm = numbers.iter().cloned().collect::<WaveletMatrix<u16>>();
+        assert_eq!(wm, WaveletMatrix::new(&numbers));
+    }
+
+    #[test]
+    fn heap_size_linear() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..40000).map(|_| rng.gen()).collect();
+        let small = WaveletMatrix::new_with_size(&numbers[..20000], 8).heap_size();
+        let large = WaveletMatrix::new_with_size(&numbers, 8).heap_size();
+        // Random bytes don't compress: at least one bit per element and row.
+        assert!(small > 20000 * 8 / 8);
+        let ratio = large as f64 / small as f64;
+        assert!(1.8 < ratio && ratio < 2.2, "ratio {} is roughly 2", ratio);
+    }
+
+    #[test]
+    fn new_auto_small() {
+        let numbers = &[4u64, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_auto(numbers);
+        assert_eq!(wm.bit_width(), 3);
+        for (i, &n) in numbers.iter().enumerate() {
+            assert_eq!(wm.access(i as u64), n);
+        }
+        for c in 0..8u64 {
+            let mut r = 0;
+            for (k, &n) in numbers.iter().enumerate() {
+                assert_eq!(wm.rank(c, k as u64), r);
+                if n == c {
+                    assert_eq!(wm.select(c, r), k as u64);
+                    r += 1;
+                }
+            }
+        }
+
+        let wm = WaveletMatrix::new_auto([0u64, 0, 0]);
+        assert_eq!(wm.bit_width(), 1);
+        assert_eq!(wm.rank(0, 3), 3);
+        assert_eq!(wm.access(2), 0);
+    }
+
+    #[derive(Default)]
+    struct NaiveBits(Vec<bool>);
+
+    impl FID for NaiveBits {
+        fn len(&self) -> u64 {
+            self.0.len() as u64
+        }
+
+        fn rank1(&self, i: u64) -> u64 {
+            self.0[..i as usize].iter().filter(|&&b| b).count() as u64
+        }
+    }
+
+    impl PushBit for NaiveBits {
+        fn push_bit(&mut self, b: bool) {
+            self.0.push(b);
+        }
+    }
+
+    #[test]
+    fn default_backend() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm: WaveletMatrix<u8, BitVector> = WaveletMatrix::new_with_backend(numbers, 3);
+        assert_eq!(wm, WaveletMatrix::new_with_size(numbers, 3));
+    }
+
+    #[test]
+    fn custom_backend() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        let naive = WaveletMatrix::<u8, NaiveBits>::new_with_backend(numbers, 3);
+        assert_eq!(naive.iter().collect::<Vec<_>>(), numbers.to_vec());
+        for c in 0..8u8 {
+            for k in 0..=numbers.len() as u64 {
+                assert_eq!(naive.rank(c, k), wm.rank(c, k));
+            }
+            for k in 0..wm.rank(c, wm.len()) {
+                assert_eq!(naive.select(c, k), wm.select(c, k));
+            }
+        }
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn new_par_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..5000).map(|_| rng.gen()).collect();
+        assert_eq!(
+            WaveletMatrix::new_par(&numbers, 16),
+            WaveletMatrix::new_with_size(&numbers, 16)
+        );
+        let numbers: Vec<u8> = (0..5000).map(|_| rng.gen_range(0..8)).collect();
+        assert_eq!(
+            WaveletMatrix::new_par(&numbers, 3),
+            WaveletMatrix::new_with_size(&numbers, 3)
+        );
+    }
+
+    // The original construction with separate buffers for zeros and ones.
+    fn build_reference(text: &[u16], size: u64) -> WaveletMatrix<u16> {
+        let mut rows: Vec<BitVector> = vec![];
+        let mut zeros: Vec<u16> = text.to_vec();
+        let mut ones: Vec<u16> = Vec::new();
+        let mut partitions: Vec<u64> = Vec::new();
+        for r in 0..size {
+            let mut bv = BitVector::new();
+            let mut new_zeros: Vec<u16> = Vec::new();
+            let mut new_ones: Vec<u16> = Vec::new();
+            for arr in &[zeros, ones] {
+                for &c in arr {
+                    let bit = (c >> (size - r - 1)) & 1 > 0;
+                    if bit {
+                        new_ones.push(c);
+                    } else {
+                        new_zeros.push(c);
+                    }
+                    bv.push(bit);
+                }
+            }
+            zeros = new_zeros;
+            ones = new_ones;
+            rows.push(bv);
+            partitions.push(zeros.len() as u64);
+        }
+        WaveletMatrix {
+            rows,
+            size,
+            len: text.len() as u64,
+            partitions,
+            _t: core::marker::PhantomData,
+        }
+    }
+
+    #[test]
+    fn construction_matches_reference() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for &(n, size) in &[(0, 16), (1, 16), (100, 3), (5000, 16), (3000, 10)] {
+            let max = ((1u32 << size) - 1) as u16;
+            let numbers: Vec<u16> = (0..n).map(|_| rng.gen_range(0..=max)).collect();
+            assert_eq!(
+                WaveletMatrix::new_with_size(&numbers, size as u64),
+                build_reference(&numbers, size as u64)
+            );
+        }
+    }
+
+    #[test]
+    fn count_distinct_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..300).map(|_| rng.gen_range(0..64)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 6);
+        for _ in 0..500 {
+            let i = rng.gen_range(0..=numbers.len());
+            let j = rng.gen_range(i..=numbers.len());
+            let d = numbers[i..j].iter().collect::<HashSet<_>>().len() as u64;
+            assert!(
+                wm.count_distinct(i as u64..j as u64) == d,
+                "wm.count_distinct({}..{}) == {}",
+                i,
+                j,
+                d
+            );
+        }
+        assert_eq!(wm.count_distinct(5..5), 0);
+        let all = numbers.iter().collect::<HashSet<_>>().len() as u64;
+        assert_eq!(wm.count_distinct(0..wm.len()), all);
+    }
+
+    #[test]
+    fn range_min_max_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..300).map(|_| rng.gen()).collect();
+        let wm = WaveletMatrix::new(&numbers);
+        for _ in 0..500 {
+            let i = rng.gen_range(0..=numbers.len());
+            let j = rng.gen_range(i..=numbers.len());
+            let window = &numbers[i..j];
+            assert_eq!(
+                wm.range_min(i as u64..j as u64),
+                window.iter().min().cloned()
+            );
+            assert_eq!(
+                wm.range_max(i as u64..j as u64),
+                window.iter().max().cloned()
+            );
+        }
+        assert_eq!(wm.range_min(10..10), None);
+        assert_eq!(wm.range_max(400..500), None);
+    }
+
+    #[test]
+    fn rank_batch_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..300).map(|_| rng.gen_range(0..16)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 4);
+        let queries: Vec<(u8, u64)> = (0..1000)
+            .map(|_| (rng.gen_range(0..16), rng.gen_range(0..350)))
+            .collect();
+        let expected: Vec<u64> = queries.iter().map(|&(c, k)| wm.rank(c, k)).collect();
+        assert_eq!(wm.rank_batch(&queries), expected);
+        assert_eq!(wm.rank_batch(&[]), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn mode_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..200).map(|_| rng.gen_range(0..8)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 3);
+        for _ in 0..500 {
+            let i = rng.gen_range(0..=numbers.len());
+            let j = rng.gen_range(i..=numbers.len());
+            let mut freq = [0u64; 8];
+            for &n in &numbers[i..j] {
+                freq[n as usize] += 1;
+            }
+            let ans = (0..8u8)
+                .filter(|&c| freq[c as usize] > 0)
+                .min_by_key(|&c| (Reverse(freq[c as usize]), c))
+                .map(|c| (c, freq[c as usize]));
+            assert!(
+                wm.mode(i as u64..j as u64) == ans,
+                "wm.mode({}..{}) == {:?}",
+                i,
+                j,
+                ans
+            );
+        }
+    }
+
+    #[test]
+    fn new_mapped_sparse() {
+        let numbers = &[1000u64, 5_000_000, 42, 1000, 42, 42, 5_000_000];
+        let (wm, table) = WaveletMatrix::new_mapped(numbers);
+        assert_eq!(table, vec![42, 1000, 5_000_000]);
+        assert_eq!(wm.bit_width(), 2);
+        for (i, &n) in numbers.iter().enumerate() {
+            assert_eq!(WaveletMatrix::unmap_symbol(&table, wm.access(i as u64)), n);
+        }
+        for &c in &table {
+            let r = WaveletMatrix::map_symbol(&table, c).unwrap();
+            for k in 0..=numbers.len() {
+                let count = numbers[..k].iter().filter(|&&n| n == c).count() as u64;
+                assert_eq!(wm.rank(r, k as u64), count);
+            }
+        }
+        assert_eq!(WaveletMatrix::map_symbol(&table, 7), None);
+    }
+
+    #[test]
+    fn to_vec_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let bytes: Vec<u8> = (0..2000).map(|_| rng.gen()).collect();
+        assert_eq!(WaveletMatrix::new(&bytes).to_vec(), bytes);
+        let words: Vec<u16> = (0..2000).map(|_| rng.gen()).collect();
+        assert_eq!(WaveletMatrix::new(&words).to_vec(), words);
+    }
+
+    #[test]
+    fn display_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        let s = wm.to_string();
+        assert!(s.contains("size = 3"));
+        assert!(s.contains("len = 12"));
+        assert!(s.contains("[4, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7]"));
+    }
+
+    #[test]
+    fn debug_empty() {
+        let wm = WaveletMatrix::new(Vec::<u8>::new());
+        assert_eq!(format!("{:?}", wm), "WaveletMatrix { (empty) }");
+        let wm = WaveletMatrix::new_with_size([1u8, 2], 0);
+        assert_eq!(format!("{:?}", wm), "WaveletMatrix { (empty) }");
+
+        let wm = WaveletMatrix::new_with_size([1u8, 2], 2);
+        assert_eq!(format!("{:?}", wm), "WaveletMatrix {\n  01\n  10\n}");
+    }
+
+    #[test]
+    fn try_new_with_size_small() {
+        let err = WaveletMatrix::try_new_with_size([1u8, 255, 3], 3).unwrap_err();
+        assert_eq!(
+            err,
+            WaveletError::SymbolTooLarge {
+                value: 255,
+                size: 3
+            }
+        );
+        assert!(err.to_string().contains("255"));
+
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::try_new_with_size(numbers, 3).unwrap();
+        assert_eq!(wm, WaveletMatrix::new_with_size(numbers, 3));
+        assert!(WaveletMatrix::try_new_with_size([255u8], 8).is_ok());
+        assert!(WaveletMatrix::try_new_with_size([u64::MAX], 64).is_ok());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn error_variants() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        assert_eq!(wm.try_access(3), Ok(5));
+        assert_eq!(
+            wm.try_access(12),
+            Err(WaveletError::IndexOutOfBounds { index: 12, len: 12 })
+        );
+        assert_eq!(wm.try_quantile(2..5, 0), Ok(3));
+        assert_eq!(
+            wm.try_quantile(2..5, 3),
+            Err(WaveletError::IndexOutOfBounds { index: 3, len: 3 })
+        );
+        assert_eq!(
+            WaveletMatrix::try_new_with_size([9u8], 3).unwrap_err(),
+            WaveletError::SymbolTooLarge { value: 9, size: 3 }
+        );
+
+        let mut buf = Vec::new();
+        wm.write_to(&mut buf).unwrap();
+        let read_err = |bytes: &[u8]| {
+            let err = WaveletMatrix::<u8>::read_from(&mut &bytes[..])
+                .err()
+                .unwrap();
+            err.get_ref()
+                .and_then(|e| e.downcast_ref::<WaveletError>())
+                .cloned()
+        };
+        assert_eq!(
+            read_err(&buf[..buf.len() - 1]),
+            Some(WaveletError::Truncated)
+        );
+        let mut bad = buf.clone();
+        bad[0] = b'X';
+        assert_eq!(
+            read_err(&bad),
+            Some(WaveletError::InvalidFormat("bad magic bytes"))
+        );
+    }
+
+    #[test]
+    fn from_str_ascii() {
+        let text = "abracadabra";
+        let wm = WaveletMatrix::from_str(text);
+        assert_eq!(wm.len(), 11);
+        assert_eq!(wm.rank_char('a', 11), 5);
+        assert_eq!(wm.rank_char('b', 9), 2);
+        assert_eq!(wm.rank_char('r', 3), 1);
+        assert_eq!(wm.rank_char('z', 11), 0);
+    }
+
+    #[test]
+    fn from_str_unicode() {
+        let text = "日本語のテキストと日本";
+        let wm = WaveletMatrix::from_str(text);
+        assert_eq!(wm.len(), text.chars().count() as u64);
+        assert_eq!(wm.rank_char('日', wm.len()), 2);
+        assert_eq!(wm.rank_char('本', 2), 1);
+        assert_eq!(wm.rank_char('の', 3), 0);
+        assert_eq!(wm.access(3), u32::from('の'));
+    }
+
+    #[test]
+    fn range_kth_largest_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..200).map(|_| rng.gen_range(0..32)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 5);
+        for _ in 0..200 {
+            let i = rng.gen_range(0..=numbers.len());
+            let j = rng.gen_range(i..=numbers.len());
+            let range = i as u64..j as u64;
+            let mut sorted = numbers[i..j].to_vec();
+            sorted.sort_by(|a, b| b.cmp(a));
+            assert_eq!(
+                wm.range_kth_largest(range.clone(), 0),
+                wm.range_max(range.clone())
+            );
+            for (k, &n) in sorted.iter().enumerate() {
+                assert!(
+                    wm.range_kth_largest(range.clone(), k as u64) == Some(n),
+                    "wm.range_kth_largest({}..{}, {}) == {}",
+                    i,
+                    j,
+                    k,
+                    n
+                );
+            }
+            assert_eq!(wm.range_kth_largest(range, sorted.len() as u64), None);
+        }
+    }
+
+    #[test]
+    fn sum_in_range_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..300).map(|_| rng.gen_range(0..1000)).collect();
+        let wm = WaveletMatrix::new_auto(&numbers);
+        for _ in 0..100 {
+            let i = rng.gen_range(0..=numbers.len());
+            let j = rng.gen_range(i..=numbers.len() + 10);
+            let naive: u64 = (i as u64..cmp::min(j, numbers.len()) as u64)
+                .map(|k| u64::from(wm.access(k)))
+                .sum();
+            assert!(
+                wm.sum_in_range(i as u64..j as u64) == naive,
+                "wm.sum_in_range({}..{}) == {}",
+                i,
+                j,
+                naive
+            );
+        }
+        assert_eq!(wm.sum_in_range(5..5), 0);
+    }
+
+    #[test]
+    fn sum_in_range_large_values() {
+        let numbers = [u64::MAX, 0, u64::MAX - 1, 1, 1 << 63, (1 << 63) - 1];
+        let wm = WaveletMatrix::new(numbers);
+        assert_eq!(wm.sum_in_range(0..2), u64::MAX);
+        assert_eq!(wm.sum_in_range(1..4), u64::MAX);
+        assert_eq!(wm.sum_in_range(4..6), u64::MAX);
+        assert_eq!(wm.sum_in_range(3..5), (1 << 63) + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "the sum of the range overflows u64")]
+    fn sum_in_range_overflow() {
+        let wm = WaveletMatrix::new([u64::MAX, 1]);
+        wm.sum_in_range(0..2);
+    }
+
+    #[test]
+    fn range_list_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..300).map(|_| rng.gen_range(0..40)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 6);
+        for _ in 0..200 {
+            let i = rng.gen_range(0..=numbers.len());
+            let j = rng.gen_range(i..=numbers.len() + 10);
+            let mut freq = BTreeMap::new();
+            for &n in &numbers[i..cmp::min(j, numbers.len())] {
+                *freq.entry(n).or_insert(0u64) += 1;
+            }
+            let expected: Vec<(u8, u64)> = freq.into_iter().collect();
+            assert_eq!(wm.range_list(i as u64..j as u64), expected);
+        }
+        assert_eq!(wm.range_list(7..7), vec![]);
+    }
+
+    #[test]
+    fn access_rank_random() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        for k in 0..numbers.len() as u64 {
+            let (v, r) = wm.access_rank(k);
+            assert_eq!(v, wm.access(k));
+            assert!(r == wm.rank(v, k + 1), "wm.access_rank({}).1 == {}", k, r);
+        }
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..300).map(|_| rng.gen_range(0..50)).collect();
+        let wm = WaveletMatrix::new(&numbers);
+        for k in 0..numbers.len() as u64 {
+            let (v, r) = wm.access_rank(k);
+            assert_eq!(v, numbers[k as usize]);
+            assert_eq!(r, wm.rank(v, k + 1));
+        }
+    }
+
+    #[test]
+    fn count_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..300).map(|_| rng.gen_range(0..16)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 4);
+        for _ in 0..500 {
+            let c = rng.gen_range(0..16);
+            let i = rng.gen_range(0..=numbers.len() as u64);
+            let j = rng.gen_range(i..=numbers.len() as u64 + 10);
+            let expected = wm.rank(c, j) - wm.rank(c, i);
+            assert!(
+                wm.count(c, i..j) == expected,
+                "wm.count({}, {}..{}) == {}",
+                c,
+                i,
+                j,
+                expected
+            );
+        }
+        assert_eq!(wm.count(16, 0..300), 0);
+        assert_eq!(wm.count(3, 10..10), 0);
+    }
+
+    #[test]
+    fn with_capacity_builder() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let mut builder = WaveletMatrix::with_capacity(numbers.len() as u64, 3);
+        for &n in numbers {
+            builder.push(n);
+        }
+        assert_eq!(builder.len(), 12);
+        assert!(builder.finish() == WaveletMatrix::new_with_size(numbers, 3));
+    }
+
+    #[test]
+    fn incremental_builder() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let mut builder = Builder::new(3);
+        assert!(builder.is_empty());
+        for &n in numbers {
+            builder.push(n);
+        }
+        assert!(builder.build() == WaveletMatrix::new_with_size(numbers, 3));
+
+        let mut builder = Builder::new(3);
+        for chunk in numbers.chunks(5) {
+            builder.extend(chunk.iter().cloned());
+        }
+        assert!(builder.build() == WaveletMatrix::new_with_size(numbers, 3));
+        assert!(Builder::<u8>::new(3).build().is_empty());
+    }
+
+    #[test]
+    fn rows_accessor() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        assert_eq!(wm.num_rows(), 3);
+        assert_eq!(wm.row(0).len(), wm.len());
+        let top: Vec<bool> = (0..12).map(|i| wm.row(0).get(i)).collect();
+        let expected: Vec<bool> = numbers.iter().map(|&n| n >= 4).collect();
+        assert_eq!(top, expected);
+    }
+
+    #[test]
+    fn histogram_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        let mut expected = Vec::new();
+        for c in 0..8u8 {
+            let count = numbers.iter().filter(|&&n| n == c).count() as u64;
+            if count > 0 {
+                expected.push((c, count));
+            }
+        }
+        assert_eq!(wm.histogram(), expected);
+        assert_eq!(WaveletMatrix::<u8>::new([]).histogram(), vec![]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn zeroth_order_entropy() {
+        let uniform: Vec<u8> = (0..8).chain(0..8).collect();
+        let wm = WaveletMatrix::new_with_size(&uniform, 3);
+        assert!((wm.zeroth_order_entropy() - 3.0).abs() < 1e-9);
+
+        let wm = WaveletMatrix::new_with_size([5u8; 10], 3);
+        assert_eq!(wm.zeroth_order_entropy(), 0.0);
+        assert_eq!(WaveletMatrix::<u8>::new([]).zeroth_order_entropy(), 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "must be at most 64")]
+    fn size_too_large() {
+        WaveletMatrix::new_with_size([0u8], 65);
+    }
+
+    #[test]
+    fn try_size_too_large() {
+        assert!(WaveletMatrix::try_new_with_size([0u8], 65) == Err(WaveletError::SizeTooLarge(65)));
+        assert!(WaveletMatrix::try_new_with_size([0u64], 64).is_ok());
+    }
+
+    fn _assert_send_sync<T: Send + Sync>() {}
+
+    fn _matrices_are_send_sync() {
+        _assert_send_sync::<WaveletMatrix<u8>>();
+        _assert_send_sync::<WaveletMatrix<u64>>();
+        _assert_send_sync::<HuffmanWaveletTree<u8>>();
+        #[cfg(feature = "mmap")]
+        _assert_send_sync::<WaveletMatrix<u8, MmapBits>>();
+    }
+
+    #[test]
+    fn shared_between_threads() {
         let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
-        let size = 3;
-        let wm = WaveletMatrix::new_with_size(numbers, size);
-        assert_eq!(wm.len, numbers.len() as u64);
-        for (i, &n) in numbers.iter().enumerate() {
-            assert!(wm.access(i as u64) == n, "wm.access({}) == {}", i, n);
+        let wm = std::sync::Arc::new(WaveletMatrix::new_with_size(numbers, 3));
+        let handles: Vec<_> = (0..8u8)
+            .map(|c| {
+                let wm = wm.clone();
+                std::thread::spawn(move || (0..=12).map(|k| wm.rank(c, k)).collect::<Vec<_>>())
+            })
+            .collect();
+        for (c, handle) in handles.into_iter().enumerate() {
+            let ranks = handle.join().unwrap();
+            for (k, &r) in ranks.iter().enumerate() {
+                let expected = numbers[..k].iter().filter(|&&n| n == c as u8).count() as u64;
+                assert!(r == expected, "wm.rank({}, {}) == {}", c, k, expected);
+            }
         }
     }
 
     #[test]
-    fn select_small() {
+    fn range_iter_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..3000).map(|_| rng.gen()).collect();
+        let wm = WaveletMatrix::new(&numbers);
+        let all: Vec<(u64, u16)> = wm.range_iter(0..wm.len()).collect();
+        let expected: Vec<(u64, u16)> = (0..).zip(wm.to_vec()).collect();
+        assert_eq!(all, expected);
+        for _ in 0..20 {
+            let i = rng.gen_range(0..=numbers.len() as u64);
+            let j = rng.gen_range(i..=numbers.len() as u64 + 10);
+            let window: Vec<(u64, u16)> = wm.range_iter(i..j).collect();
+            assert_eq!(window, expected[i as usize..cmp::min(j, wm.len()) as usize]);
+        }
+        assert_eq!(wm.range_iter(5000..6000).count(), 0);
+    }
+
+    #[test]
+    fn positions_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..300).map(|_| rng.gen_range(0..8)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 3);
+        for _ in 0..200 {
+            let c = rng.gen_range(0..9);
+            let i = rng.gen_range(0..=numbers.len());
+            let j = rng.gen_range(i..=numbers.len() + 10);
+            let expected: Vec<u64> = (i..cmp::min(j, numbers.len()))
+                .filter(|&p| numbers[p] == c)
+                .map(|p| p as u64)
+                .collect();
+            assert_eq!(wm.positions(c, i as u64..j as u64), expected);
+        }
+    }
+
+    #[test]
+    fn level_primitives() {
         let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
-        let size = 3;
-        let wm = WaveletMatrix::new_with_size(numbers, size);
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        for (k, &n) in numbers.iter().enumerate() {
+            let mut s = 0;
+            let mut i = k as u64;
+            for level in 0..wm.num_rows() {
+                let bit = (n >> (2 - level)) & 1 > 0;
+                assert_eq!(wm.row(level).get(i), bit);
+                s = wm.level_rank(level, bit, s);
+                i = wm.level_rank(level, bit, i);
+                if bit {
+                    s += wm.level_partition(level);
+                    i += wm.level_partition(level);
+                }
+            }
+            let r = wm.rank(n, k as u64);
+            assert!(i - s == r, "leaf offset of position {} == {}", k, r);
+        }
+        assert_eq!(wm.level_partition(0), 6);
+        assert_eq!(wm.level_rank(0, true, 12), 6);
+    }
 
-        let mut ans: Vec<Vec<u64>> = vec![vec![]; 1 << size];
-        for (i, &n) in numbers.iter().enumerate() {
-            ans[n as usize].push(i as u64);
+    #[test]
+    #[should_panic(expected = "must not exceed the length")]
+    fn level_rank_out_of_bounds() {
+        let wm = WaveletMatrix::new_with_size([1u8, 2, 3], 2);
+        wm.level_rank(0, false, 4);
+    }
+
+    #[test]
+    fn percentile_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..300).map(|_| rng.gen()).collect();
+        let wm = WaveletMatrix::new(&numbers);
+        for _ in 0..200 {
+            let i = rng.gen_range(0..=numbers.len() as u64);
+            let j = rng.gen_range(i..=numbers.len() as u64);
+            assert_eq!(wm.percentile(i..j, 0.0), wm.range_min(i..j));
+            assert_eq!(wm.percentile(i..j, 1.0), wm.range_max(i..j));
+            assert_eq!(wm.percentile(i..j, -3.0), wm.range_min(i..j));
+            assert_eq!(wm.percentile(i..j, 7.5), wm.range_max(i..j));
+            if i < j {
+                let p: f64 = rng.gen();
+                let k = (p * (j - i - 1) as f64) as u64;
+                assert!(
+                    wm.percentile(i..j, p) == Some(wm.quantile(i..j, k)),
+                    "wm.percentile({}..{}, {}) == wm.quantile({}..{}, {})",
+                    i,
+                    j,
+                    p,
+                    i,
+                    j,
+                    k
+                );
+            }
         }
+        assert_eq!(wm.percentile(3..3, 0.5), None);
+    }
 
-        for (c, a) in ans.iter().enumerate() {
-            for (k, &i) in a.iter().enumerate() {
+    #[test]
+    fn co_range_count_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let a: Vec<u8> = (0..300).map(|_| rng.gen_range(0..32)).collect();
+        let b: Vec<u8> = (0..300).map(|_| rng.gen_range(0..32)).collect();
+        let wa = WaveletMatrix::new_with_size(&a, 5);
+        let wb = WaveletMatrix::new_with_size(&b, 5);
+        for _ in 0..100 {
+            let lo_a = rng.gen_range(0..32);
+            let hi_a = rng.gen_range(lo_a..=40);
+            let lo_b = rng.gen_range(0..32);
+            let hi_b = rng.gen_range(lo_b..=40);
+            let naive = a
+                .iter()
+                .zip(&b)
+                .filter(|&(&x, &y)| (lo_a..hi_a).contains(&x) && (lo_b..hi_b).contains(&y))
+                .count() as u64;
+            assert!(
+                wa.co_range_count(&wb, lo_a..hi_a, lo_b..hi_b) == naive,
+                "wa.co_range_count(&wb, {}..{}, {}..{}) == {}",
+                lo_a,
+                hi_a,
+                lo_b,
+                hi_b,
+                naive
+            );
+        }
+
+        let wa = WaveletMatrix::new([u64::MAX, 0, 1 << 63]);
+        let wb = WaveletMatrix::new([1u64, 2, 3]);
+        assert_eq!(wa.co_range_count(&wb, 1 << 63..u64::MAX, 0..10), 1);
+        assert_eq!(wa.co_range_count(&wb, 0..u64::MAX, 2..4), 2);
+        assert_eq!(wa.co_range_count(&wb, 5..5, 0..10), 0);
+    }
+
+    #[test]
+    fn contains_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        for c in 0..8u8 {
+            assert!(wm.contains(c), "wm.contains({})", c);
+        }
+        assert!(!wm.contains(8));
+
+        let numbers = &[4u8, 6, 6, 1, 1, 4];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        for c in 0..16u8 {
+            let expected = numbers.contains(&c);
+            assert!(
+                wm.contains(c) == expected,
+                "wm.contains({}) == {}",
+                c,
+                expected
+            );
+        }
+        assert!(!WaveletMatrix::<u8>::new([]).contains(0));
+    }
+
+    #[test]
+    fn top_k_in_value_range_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..300).map(|_| rng.gen_range(0..24)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 5);
+        for _ in 0..200 {
+            let i = rng.gen_range(0..=numbers.len());
+            let j = rng.gen_range(i..=numbers.len());
+            let lo = rng.gen_range(0..32);
+            let hi = rng.gen_range(lo..=40);
+            let k = rng.gen_range(0..6);
+            let mut freq = BTreeMap::new();
+            for &n in numbers[i..j].iter().filter(|&&n| lo <= n && n < hi) {
+                *freq.entry(n).or_insert(0u64) += 1;
+            }
+            let mut expected: Vec<(u8, u64)> = freq.into_iter().collect();
+            expected.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            expected.truncate(k);
+            assert!(
+                wm.top_k_in_value_range(i as u64..j as u64, lo..hi, k) == expected,
+                "wm.top_k_in_value_range({}..{}, {}..{}, {}) == {:?}",
+                i,
+                j,
+                lo,
+                hi,
+                k,
+                expected
+            );
+        }
+        assert_eq!(wm.top_k_in_value_range(0..300, 4..4, 3), vec![]);
+    }
+
+    #[test]
+    fn prepared_rank_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        for c in 0..10u8 {
+            let prepared = wm.prepared_rank(c);
+            for k in 0..=numbers.len() as u64 + 2 {
                 assert!(
-                    wm.select(c as u8, k as u64) == i,
-                    "wm.select({}, {}) == {}",
+                    prepared.rank(k) == wm.rank(c, k),
+                    "wm.prepared_rank({}).rank({}) == {}",
                     c,
                     k,
-                    i
+                    wm.rank(c, k)
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn from_reader() {
+        let wm = WaveletMatrix::from_reader(&b"4 7 6 5"[..], 3).unwrap();
+        assert_eq!(wm.len(), 4);
+        for (i, &n) in [4u64, 7, 6, 5].iter().enumerate() {
+            assert_eq!(wm.access(i as u64), n);
+        }
+
+        let input = "4 7 6 5\n3 2\t1 0\n\n 1 4 1 7 \n";
+        let wm = WaveletMatrix::from_reader(input.as_bytes(), 3).unwrap();
+        let numbers = &[4u64, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        assert!(wm == WaveletMatrix::new_with_size(numbers, 3));
+
+        let err = WaveletMatrix::from_reader(&b"4 x 6"[..], 3).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = WaveletMatrix::from_reader(&b"4 9"[..], 3).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let numbers = &[4u16, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new(numbers);
+        assert_eq!(wm.num_rows(), 16);
+        let shrunk = wm.shrink_to_fit();
+        assert_eq!(shrunk.num_rows(), 3);
+        assert_eq!(shrunk.to_vec(), wm.to_vec());
+        for c in 0..8u16 {
+            for k in 0..=numbers.len() as u64 {
+                assert_eq!(shrunk.rank(c, k), wm.rank(c, k));
+            }
+        }
+        assert_eq!(shrunk.quantile(2..9, 3), wm.quantile(2..9, 3));
+        assert_eq!(WaveletMatrix::<u16>::new([]).shrink_to_fit().num_rows(), 1);
+    }
+
+    #[test]
+    fn sorted_with_positions_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..300).map(|_| rng.gen_range(0..20)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 5);
+        for _ in 0..100 {
+            let i = rng.gen_range(0..=numbers.len());
+            let j = rng.gen_range(i..=numbers.len() + 10);
+            let mut expected: Vec<(u8, u64)> = (i..cmp::min(j, numbers.len()))
+                .map(|p| (numbers[p], p as u64))
+                .collect();
+            expected.sort_by_key(|&(n, _)| n);
+            assert_eq!(wm.sorted_with_positions(i as u64..j as u64), expected);
+        }
+    }
+
+    #[test]
+    fn row_popcounts_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..500).map(|_| rng.gen_range(0..1000)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 10);
+        let popcounts = wm.row_popcounts();
+        assert_eq!(popcounts.len(), 10);
+        for (r, &ones) in popcounts.iter().enumerate() {
+            assert_eq!(ones, wm.row(r).rank1(wm.len()));
+            assert_eq!(ones + wm.level_partition(r), wm.len());
+            let bit = 1 << (9 - r);
+            assert_eq!(
+                ones,
+                numbers.iter().filter(|&&n| n & bit > 0).count() as u64
+            );
+        }
+    }
+
+    #[test]
+    fn prev_next_occurrence_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        for c in 0..9u8 {
+            for p in 0..numbers.len() + 3 {
+                let prev = (0..cmp::min(p, numbers.len()))
+                    .rev()
+                    .find(|&q| numbers[q] == c)
+                    .map(|q| q as u64);
+                let next = (p + 1..numbers.len())
+                    .find(|&q| numbers[q] == c)
+                    .map(|q| q as u64);
+                assert!(
+                    wm.prev_occurrence(c, p as u64) == prev,
+                    "wm.prev_occurrence({}, {}) == {:?}",
+                    c,
+                    p,
+                    prev
+                );
+                assert!(
+                    wm.next_occurrence(c, p as u64) == next,
+                    "wm.next_occurrence({}, {}) == {:?}",
+                    c,
+                    p,
+                    next
+                );
+            }
+        }
+        assert_eq!(wm.prev_occurrence(4, 0), None);
+        assert_eq!(wm.next_occurrence(7, 11), None);
+        assert_eq!(wm.next_occurrence(7, u64::MAX), None);
+    }
+
+    #[test]
+    fn sorted_rank_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..300).map(|_| rng.gen_range(0..20)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 5);
+        let mut order: Vec<usize> = (0..numbers.len()).collect();
+        order.sort_by_key(|&i| numbers[i]);
+        let mut seen = vec![false; numbers.len()];
+        for (rank, &i) in order.iter().enumerate() {
+            let r = wm.sorted_rank(i as u64);
+            assert!(r == rank as u64, "wm.sorted_rank({}) == {}", i, rank);
+            seen[r as usize] = true;
+        }
+        assert!(seen.iter().all(|&b| b));
+    }
+
+    #[test]
+    fn quantile_batch() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        let mut queries = Vec::new();
+        for i in 0..numbers.len() as u64 {
+            for j in (i + 1)..=numbers.len() as u64 {
+                queries.push((i..j, (i * 7 + j) % (j - i)));
+            }
+        }
+        let answers = wm.quantile_batch(&queries);
+        assert_eq!(answers.len(), queries.len());
+        for ((range, k), &a) in queries.iter().zip(&answers) {
+            assert!(
+                a == wm.quantile(range.clone(), *k),
+                "wm.quantile({:?}, {}) == {}",
+                range,
+                k,
+                a
+            );
+        }
+        assert!(wm.quantile_batch(&[]).is_empty());
+    }
+
+    #[test]
+    fn distinct_iter() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..500).map(|_| rng.gen_range(0..200)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 8);
+        assert_eq!(
+            wm.distinct_iter(0..wm.len()).collect::<Vec<_>>(),
+            wm.histogram()
+        );
+        for _ in 0..100 {
+            let i = rng.gen_range(0..=numbers.len() as u64);
+            let j = rng.gen_range(i..=numbers.len() as u64 + 5);
+            assert_eq!(
+                wm.distinct_iter(i..j).collect::<Vec<_>>(),
+                wm.range_list(i..j)
+            );
+        }
+        assert_eq!(
+            wm.distinct_iter(0..wm.len()).next(),
+            wm.histogram().first().cloned()
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "must be less than the number of occurrences")]
+    fn select_missing_occurrence() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        wm.select(1, 3);
+    }
+
+    #[test]
+    fn from_dna() {
+        let seq = b"GATTACAgattaca";
+        let wm = WaveletMatrix::from_dna(seq).unwrap();
+        assert_eq!(wm.len(), 14);
+        assert_eq!(wm.bit_width(), 2);
+        assert_eq!(wm.rank_base(b'A', 14), 6);
+        assert_eq!(wm.rank_base(b'c', 14), 2);
+        assert_eq!(wm.rank_base(b'G', 14), 2);
+        assert_eq!(wm.rank_base(b'T', 7), 2);
+        assert_eq!(wm.rank_base(b'N', 14), 0);
+        assert_eq!(wm.select_base(b'T', 3), Some(10));
+        assert_eq!(wm.select_base(b'G', 2), None);
+        assert_eq!(wm.select_base(b'N', 0), None);
+        assert_eq!(
+            WaveletMatrix::from_dna(b"ACGNT").unwrap_err(),
+            WaveletError::InvalidNucleotide {
+                byte: b'N',
+                position: 3
+            }
+        );
+    }
+
+    #[test]
+    fn mode_with_positions() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        assert_eq!(wm.mode_with_positions(0..12, 10), Some((1, vec![6, 8, 10])));
+        assert_eq!(wm.mode_with_positions(0..12, 2), Some((1, vec![6, 8])));
+        assert_eq!(wm.mode_with_positions(0..12, 0), Some((1, vec![])));
+        assert_eq!(wm.mode_with_positions(9..12, 5), Some((1, vec![10])));
+        assert_eq!(wm.mode_with_positions(0..4, 5), Some((4, vec![0])));
+        assert_eq!(wm.mode_with_positions(1..2, 5), Some((7, vec![1])));
+        assert_eq!(wm.mode_with_positions(5..5, 5), None);
+    }
+
+    #[test]
+    fn access_as() {
+        let numbers = &[255u8, 0, 128, 127, 255, 1];
+        let wm = WaveletMatrix::new_with_size(numbers, 8);
+        for (i, &n) in numbers.iter().enumerate() {
+            assert_eq!(wm.access(i as u64), n);
+            assert_eq!(wm.access_as::<u8>(i as u64), Some(n));
+            assert_eq!(wm.access_as::<u64>(i as u64), Some(u64::from(n)));
+        }
+        assert_eq!(wm.access_as::<i8>(0), None);
+        assert_eq!(wm.access_as::<i8>(3), Some(127));
+
+        let wide = WaveletMatrix::new_with_size([300u16, 7], 9);
+        assert_eq!(wide.access_as::<u8>(0), None);
+        assert_eq!(wide.access_as::<u8>(1), Some(7));
+    }
+
+    #[test]
+    fn longest_run() {
+        let numbers = &[1u8, 1, 0, 3, 3, 3, 1, 3, 3, 3, 3, 0];
+        let wm = WaveletMatrix::new_with_size(numbers, 2);
+        assert_eq!(wm.longest_run(3), 4);
+        assert_eq!(wm.longest_run(1), 2);
+        assert_eq!(wm.longest_run(0), 1);
+        assert_eq!(wm.longest_run(2), 0);
+        assert_eq!(wm.longest_run(7), 0);
+        assert_eq!(WaveletMatrix::new_with_size([5u8; 9], 3).longest_run(5), 9);
+    }
+
+    #[test]
+    fn count_of_min_max() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        for i in 0..numbers.len() {
+            for j in i..=numbers.len() + 2 {
+                let window = &numbers[i..cmp::min(j, numbers.len())];
+                let count = |m: Option<&u8>| {
+                    m.map_or(0, |m| window.iter().filter(|&n| n == m).count() as u64)
+                };
+                let min = count(window.iter().min());
+                let max = count(window.iter().max());
+                let range = i as u64..j as u64;
+                assert!(
+                    wm.count_of_min(range.clone()) == min,
+                    "wm.count_of_min({:?}) == {}",
+                    range,
+                    min
+                );
+                assert!(
+                    wm.count_of_max(range.clone()) == max,
+                    "wm.count_of_max({:?}) == {}",
+                    range,
+                    max
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn row_as_u64s() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..200).map(|_| rng.gen_range(0..16)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 4);
+        for r in 0..wm.num_rows() {
+            let words = wm.row_as_u64s(r);
+            assert_eq!(words.len(), 4);
+            for i in 0..wm.len() {
+                let bit = (words[(i / 64) as usize] >> (i % 64)) & 1 > 0;
+                assert!(
+                    bit == wm.row(r).get(i),
+                    "wm.row_as_u64s({}) has bit {} == {}",
+                    r,
+                    i,
+                    bit
+                );
+            }
+            assert_eq!(words[3] >> 8, 0);
+        }
+        assert!(WaveletMatrix::<u8>::new_with_size([], 3)
+            .row_as_u64s(0)
+            .is_empty());
+    }
+
+    #[test]
+    fn from_runs() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let runs: Vec<(u8, u64)> = (0..100)
+            .map(|_| (rng.gen_range(0..32), rng.gen_range(0..6)))
+            .collect();
+        let expanded: Vec<u8> = runs
+            .iter()
+            .flat_map(|&(c, n)| core::iter::repeat_n(c, n as usize))
+            .collect();
+        let wm = WaveletMatrix::from_runs(&runs, 5);
+        assert!(wm == WaveletMatrix::new_with_size(&expanded, 5));
+        assert_eq!(wm.to_vec(), expanded);
+        assert!(WaveletMatrix::<u8>::from_runs(&[], 3).is_empty());
+        assert!(WaveletMatrix::<u8>::from_runs(&[(3, 0)], 3).is_empty());
+    }
+
+    #[test]
+    fn kth_smallest_largest() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        let mut sorted = numbers.to_vec();
+        sorted.sort();
+        for (k, &n) in sorted.iter().enumerate() {
+            assert!(
+                wm.kth_smallest(k as u64) == Some(n),
+                "wm.kth_smallest({}) == {}",
+                k,
+                n
+            );
+            assert!(
+                wm.kth_largest((sorted.len() - 1 - k) as u64) == Some(n),
+                "wm.kth_largest({}) == {}",
+                sorted.len() - 1 - k,
+                n
+            );
+        }
+        assert_eq!(wm.kth_smallest(12), None);
+        assert_eq!(wm.kth_largest(12), None);
+    }
+
+    #[test]
+    fn access_with_total() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..300).map(|_| rng.gen_range(0..40)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 6);
+        for k in 0..wm.len() {
+            let c = wm.access(k);
+            assert!(
+                wm.access_with_total(k) == (c, wm.rank(c, wm.len())),
+                "wm.access_with_total({}) == ({}, {})",
+                k,
+                c,
+                wm.rank(c, wm.len())
+            );
+        }
+    }
+
+    #[test]
+    fn rank_oversized_symbol() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        // 9 and 15 share their low 3 bits with 1 and 7, which do occur.
+        for c in [8u8, 9, 15, 255] {
+            assert!(wm.rank(c, 12) == 0, "wm.rank({}, 12) == 0", c);
+            assert!(
+                wm.rank_range(c, 2..12) == 0,
+                "wm.rank_range({}, 2..12) == 0",
+                c
+            );
+        }
+        assert_eq!(wm.rank(1, 12), 3);
+    }
+
+    #[test]
+    fn try_rank() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        for c in 0..8u8 {
+            assert_eq!(wm.try_rank(c, 9), Ok(wm.rank(c, 9)));
+        }
+        assert_eq!(wm.try_rank(1, 12), Ok(3));
+        assert_eq!(
+            wm.try_rank(12, 12),
+            Err(WaveletError::SymbolTooLarge { value: 12, size: 3 })
+        );
+        assert!(WaveletMatrix::new([u64::MAX]).try_rank(u64::MAX, 1) == Ok(1));
+    }
+
+    #[test]
+    fn concat() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        for i in 0..=numbers.len() {
+            let a = WaveletMatrix::new_with_size(&numbers[..i], 3);
+            let b = WaveletMatrix::new_with_size(&numbers[i..], 3);
+            let ab = WaveletMatrix::concat(&a, &b);
+            assert!(
+                ab == WaveletMatrix::new_with_size(numbers, 3),
+                "concat at {}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn intra_rank() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..300).map(|_| rng.gen_range(0..20)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 5);
+        for k in 0..wm.len() {
+            let r = wm.rank(wm.access(k), k);
+            assert!(wm.intra_rank(k) == r, "wm.intra_rank({}) == {}", k, r);
+        }
+    }
+
+    #[test]
+    fn default_is_empty() {
+        let wm = WaveletMatrix::<u8>::default();
+        assert_eq!(wm.len(), 0);
+        assert_eq!(wm.bit_width(), 8);
+        assert_eq!(wm.rank(3, 10), 0);
+        assert_eq!(wm.get(0), None);
+        assert!(wm == WaveletMatrix::new([]));
+        assert_eq!(format!("{:?}", wm), "WaveletMatrix { (empty) }");
+
+        let mut taken = WaveletMatrix::new([1u16, 2, 3]);
+        assert_eq!(core::mem::take(&mut taken).len(), 3);
+        assert!(taken.is_empty());
+        assert!(WaveletMatrix::<u32, WordBits>::default().is_empty());
+    }
+
+    #[test]
+    fn decode_into() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..1000).map(|_| rng.gen_range(0..500)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 9);
+        let mut out = Vec::new();
+        let mut buf = Vec::new();
+        for start in (0..wm.len()).step_by(37) {
+            buf.clear();
+            wm.decode_into(start..start + 37, &mut buf);
+            assert_eq!(
+                &buf[..],
+                &numbers[start as usize..cmp::min(start as usize + 37, 1000)]
+            );
+            out.extend_from_slice(&buf);
+        }
+        assert_eq!(out, numbers);
+        wm.decode_into(990..2000, &mut out);
+        assert_eq!(out.len(), 1010);
+        wm.decode_into(2000..3000, &mut out);
+        assert_eq!(out.len(), 1010);
+    }
+
+    #[test]
+    fn count_between_values_at() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        for i in 0..numbers.len() {
+            for j in 0..numbers.len() {
+                let lo = cmp::min(numbers[i], numbers[j]);
+                let hi = cmp::max(numbers[i], numbers[j]);
+                for (s, e) in [(0, 12), (3, 9), (5, 5), (10, 20)] {
+                    let naive = numbers[cmp::min(s, 12)..cmp::min(e, 12)]
+                        .iter()
+                        .filter(|&&n| lo < n && n < hi)
+                        .count() as u64;
+                    assert!(
+                        wm.count_between_values_at(s as u64..e as u64, i as u64, j as u64) == naive,
+                        "wm.count_between_values_at({}..{}, {}, {}) == {}",
+                        s,
+                        e,
+                        i,
+                        j,
+                        naive
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rank_scan() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        for c in 0..9u8 {
+            let ranks: Vec<u64> = (1..=wm.len()).map(|k| wm.rank(c, k)).collect();
+            assert!(
+                wm.rank_scan(c).collect::<Vec<_>>() == ranks,
+                "wm.rank_scan({}) == {:?}",
+                c,
+                ranks
+            );
+        }
+        assert_eq!(WaveletMatrix::<u8>::new([]).rank_scan(0).count(), 0);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..2000).map(|_| rng.gen_range(0..40)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 6);
+        for c in 0..64u16 {
+            let mut r = 0;
+            for (k, rank) in wm.rank_scan(c).enumerate() {
+                if numbers[k] == c {
+                    r += 1;
+                }
+                assert_eq!(rank, r);
+            }
+        }
+    }
+
+    #[test]
+    fn frequencies_desc() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        let freqs = wm.frequencies_desc();
+        assert_eq!(
+            freqs,
+            vec![
+                (1, 3),
+                (4, 2),
+                (7, 2),
+                (0, 1),
+                (2, 1),
+                (3, 1),
+                (5, 1),
+                (6, 1)
+            ]
+        );
+        assert_eq!(freqs.iter().map(|&(_, c)| c).sum::<u64>(), wm.len());
+        assert!(WaveletMatrix::<u8>::new([]).frequencies_desc().is_empty());
+    }
+
+    #[test]
+    fn new_dedup() {
+        let numbers = vec![5u8; 1000];
+        let wm = WaveletMatrix::new_with_size(&numbers, 8);
+        let dedup = WaveletMatrix::<u8, BitVector>::new_dedup(&numbers, 8);
+        assert!(dedup.heap_size() < wm.heap_size());
+        // Every row is all zeros or, for the bits set in 5, all ones.
+        let distinct = dedup.row(0).heap_size() + dedup.row(5).heap_size();
+        assert_eq!(dedup.heap_size(), distinct + 8 * 8);
+        let copy = dedup.clone();
+        assert_eq!(dedup.heap_size(), copy.heap_size());
+        assert!(copy == dedup);
+        assert_eq!(dedup.to_vec(), numbers);
+        for c in 0..8u8 {
+            for k in (0..=1000).step_by(99) {
+                assert_eq!(dedup.rank(c, k), wm.rank(c, k));
+            }
+        }
+        assert_eq!(dedup.select(5, 777), 777);
+        assert_eq!(dedup.quantile(10..20, 3), 5);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..1000).map(|_| rng.gen()).collect();
+        let wm = WaveletMatrix::new(&numbers);
+        let dedup = WaveletMatrix::<u8, BitVector>::new_dedup(&numbers, 8);
+        assert_eq!(dedup.heap_size(), wm.heap_size());
+        assert_eq!(dedup.to_vec(), numbers);
+    }
+
+    #[test]
+    fn validate() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        assert_eq!(wm.validate(), Ok(()));
+        assert_eq!(WaveletMatrix::<u8>::new([]).validate(), Ok(()));
+
+        let mut bad = wm.clone();
+        bad.partitions[1] += 1;
+        assert_eq!(
+            bad.validate(),
+            Err("partition 1 is 8 but row 1 has 7 zeros".to_string())
+        );
+
+        let mut bad = wm.clone();
+        bad.rows[2].push(true);
+        assert_eq!(
+            bad.validate(),
+            Err("row 2 has 13 bits but the length is 12".to_string())
+        );
+
+        let mut bad = wm.clone();
+        bad.partitions.pop();
+        assert_eq!(
+            bad.validate(),
+            Err("2 partitions for a bit width of 3".to_string())
+        );
+    }
+
+    #[test]
+    fn range_freq_inclusive() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        for lo in 0..9u8 {
+            for hi in 0..9u8 {
+                let naive = numbers[2..11]
+                    .iter()
+                    .filter(|&&n| lo <= n && n <= hi)
+                    .count() as u64;
+                assert!(
+                    wm.range_freq_inclusive(2..11, lo, hi) == naive,
+                    "wm.range_freq_inclusive(2..11, {}, {}) == {}",
+                    lo,
+                    hi,
+                    naive
                 );
             }
         }
+
+        let wm = WaveletMatrix::new([0u8, 255, 128, 255, 3]);
+        assert_eq!(wm.range_freq_inclusive(0..5, 0, u8::MAX), 5);
+        assert_eq!(wm.range_freq_inclusive(0..5, 200, u8::MAX), 2);
+        assert_eq!(wm.range_freq_inclusive(0..5, u8::MAX, u8::MAX), 2);
+        let wm = WaveletMatrix::new([u64::MAX, 1, u64::MAX]);
+        assert_eq!(wm.range_freq_inclusive(0..3, 1, u64::MAX), 3);
+        assert_eq!(wm.range_freq_inclusive(1..3, 2, u64::MAX), 1);
+    }
+
+    #[test]
+    fn to_dot() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let wm = WaveletMatrix::new_with_size(numbers, 3);
+        let dot = wm.to_dot();
+        assert!(dot.starts_with("digraph"));
+        assert!(dot.contains("n0_0 [label=\"[0, 12)\"];"));
+        assert!(dot.contains("n0_0 -> n1_4 [label=\"1\"];"));
+        assert!(dot.contains("n3_1 [label=\"[5, 8) = 1\"];"));
+        assert_eq!(dot.matches(" = ").count(), 8);
+        assert!(dot.trim_end().ends_with('}'));
+        assert!(WaveletMatrix::<u8>::new([]).to_dot().contains("digraph"));
+    }
+
+    #[test]
+    fn ranks_at() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u8> = (0..1000).map(|_| rng.gen_range(0..16)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 4);
+        let positions: Vec<u64> = (0..300).map(|_| rng.gen_range(0..1100)).collect();
+        for c in 0..17u8 {
+            let ranks: Vec<u64> = positions.iter().map(|&p| wm.rank(c, p)).collect();
+            assert!(
+                wm.ranks_at(c, &positions) == ranks,
+                "wm.ranks_at({}, ..) == {:?}",
+                c,
+                ranks
+            );
+        }
+        assert!(wm.ranks_at(3, &[]).is_empty());
     }
 
     #[test]
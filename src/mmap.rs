@@ -0,0 +1,214 @@
+use std::io;
+use std::sync::Arc;
+
+use fid::FID;
+use memmap2::Mmap;
+
+use crate::backend::{rank1_in_block, HeapSize, BLOCK_WORDS};
+use crate::{check_size, WaveletError, WaveletMatrix, FORMAT_VERSION, INCONSISTENT, MAGIC};
+
+/// A bit row that reads its bits from a memory-mapped file.
+///
+/// Only a rank directory with one entry per 512 bits is kept on the heap; the
+/// bits themselves stay in the mapped region.
+#[derive(Clone)]
+pub struct MmapBits {
+    map: Arc<Mmap>,
+    offset: usize,
+    len: u64,
+    blocks: Vec<u64>,
+}
+
+impl MmapBits {
+    fn new(map: Arc<Mmap>, offset: usize, len: u64) -> Self {
+        let words = (len as usize).div_ceil(64);
+        let mut bits = MmapBits {
+            map,
+            offset,
+            len,
+            blocks: Vec::with_capacity(words / BLOCK_WORDS + 1),
+        };
+        let mut ones = 0;
+        for i in 0..words {
+            if i % BLOCK_WORDS == 0 {
+                bits.blocks.push(ones);
+            }
+            ones += u64::from(bits.word(i).count_ones());
+        }
+        bits.blocks.push(ones);
+        bits
+    }
+
+    fn word(&self, i: usize) -> u64 {
+        let p = self.offset + i * 8;
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&self.map[p..p + 8]);
+        u64::from_le_bytes(buf)
+    }
+}
+
+impl FID for MmapBits {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn rank1(&self, i: u64) -> u64 {
+        // `blocks` has an extra entry holding the total, which covers `i == len`
+        // on a block boundary.
+        let block = i as usize / 64 / BLOCK_WORDS;
+        rank1_in_block(BLOCK_WORDS, self.blocks[block], |j| self.word(j), i)
+    }
+
+    fn get(&self, i: u64) -> bool {
+        (self.word(i as usize / 64) >> (i % 64)) & 1 > 0
+    }
+}
+
+impl HeapSize for MmapBits {
+    fn heap_size(&self) -> usize {
+        self.blocks.capacity() * 8
+    }
+}
+
+impl<T> WaveletMatrix<T, MmapBits> {
+    /// Builds a matrix whose rows read directly from `map`, which must hold
+    /// data in the format written by `write_to`.
+    ///
+    /// The rows are not copied, but the result is checked with `validate`
+    /// like the output of `read_from`. Malformed or truncated input is
+    /// reported as an `io::Error` wrapping a `WaveletError`.
+    pub fn from_mmap(map: Mmap) -> io::Result<Self> {
+        let read_u64 = |p: usize| -> io::Result<u64> {
+            let bytes = map.get(p..p + 8).ok_or(WaveletError::Truncated)?;
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(bytes);
+            Ok(u64::from_le_bytes(buf))
+        };
+        if map.len() < 8 {
+            return Err(WaveletError::Truncated.into());
+        }
+        if &map[0..4] != MAGIC {
+            return Err(WaveletError::InvalidFormat("bad magic bytes").into());
+        }
+        let mut version = [0u8; 4];
+        version.copy_from_slice(&map[4..8]);
+        if u32::from_le_bytes(version) != FORMAT_VERSION {
+            return Err(WaveletError::InvalidFormat("unsupported format version").into());
+        }
+        let size = read_u64(8)?;
+        check_size::<T>(size)?;
+        let len = read_u64(16)?;
+        let mut partitions = Vec::with_capacity(size as usize);
+        for r in 0..size as usize {
+            partitions.push(read_u64(24 + r * 8)?);
+        }
+        let row_bytes = len
+            .div_ceil(64)
+            .checked_mul(8)
+            .ok_or(WaveletError::InvalidFormat("length overflows"))?;
+        let start = 24 + size * 8;
+        let end = row_bytes
+            .checked_mul(size)
+            .and_then(|b| b.checked_add(start))
+            .ok_or(WaveletError::InvalidFormat("length overflows"))?;
+        if end > map.len() as u64 {
+            return Err(WaveletError::Truncated.into());
+        }
+        let map = Arc::new(map);
+        let rows = (0..size)
+            .map(|r| MmapBits::new(map.clone(), (start + r * row_bytes) as usize, len))
+            .collect();
+        let wm = WaveletMatrix {
+            rows,
+            size,
+            len,
+            partitions,
+            _t: core::marker::PhantomData,
+        };
+        wm.validate()
+            .map_err(|_| WaveletError::InvalidFormat(INCONSISTENT))?;
+        Ok(wm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::fs::{self, File};
+
+    fn map_matrix(wm: &WaveletMatrix<u16>, name: &str) -> WaveletMatrix<u16, MmapBits> {
+        let path =
+            std::env::temp_dir().join(format!("wavelet-tree-{}-{}", name, std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        wm.write_to(&mut file).unwrap();
+        drop(file);
+        let file = File::open(&path).unwrap();
+        let map = unsafe { Mmap::map(&file).unwrap() };
+        fs::remove_file(&path).unwrap();
+        WaveletMatrix::from_mmap(map).unwrap()
+    }
+
+    #[test]
+    fn same_answers_as_heap() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..3000).map(|_| rng.gen_range(0..300)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 9);
+        let mapped = map_matrix(&wm, "random");
+        assert_eq!(mapped.len(), wm.len());
+        assert_eq!(mapped.bit_width(), 9);
+        for i in 0..wm.len() {
+            assert_eq!(mapped.access(i), wm.access(i));
+        }
+        for _ in 0..500 {
+            let c = rng.gen_range(0..300);
+            let k = rng.gen_range(0..=wm.len());
+            assert!(
+                mapped.rank(c, k) == wm.rank(c, k),
+                "mapped.rank({}, {}) == {}",
+                c,
+                k,
+                wm.rank(c, k)
+            );
+        }
+        assert_eq!(mapped.select(numbers[100], 0), wm.select(numbers[100], 0));
+    }
+
+    #[test]
+    fn empty_and_truncated() {
+        let wm = WaveletMatrix::<u16>::new_with_size([], 4);
+        assert!(map_matrix(&wm, "empty").is_empty());
+
+        let path = std::env::temp_dir().join(format!("wavelet-tree-short-{}", std::process::id()));
+        let mut buf = Vec::new();
+        WaveletMatrix::new_with_size([1u16, 2, 3], 2)
+            .write_to(&mut buf)
+            .unwrap();
+        fs::write(&path, &buf[..buf.len() - 1]).unwrap();
+        let map = unsafe { Mmap::map(&File::open(&path).unwrap()).unwrap() };
+        fs::remove_file(&path).unwrap();
+        let err = WaveletMatrix::<u16, MmapBits>::from_mmap(map)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn inconsistent_partition() {
+        let path = std::env::temp_dir().join(format!("wavelet-tree-bad-{}", std::process::id()));
+        let mut buf = Vec::new();
+        WaveletMatrix::new_with_size([1u16, 2, 3, 0, 2], 2)
+            .write_to(&mut buf)
+            .unwrap();
+        // The first partition follows the magic, the version, `size` and `len`.
+        buf[24] += 1;
+        fs::write(&path, &buf).unwrap();
+        let map = unsafe { Mmap::map(&File::open(&path).unwrap()).unwrap() };
+        fs::remove_file(&path).unwrap();
+        let err = WaveletMatrix::<u16, MmapBits>::from_mmap(map)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
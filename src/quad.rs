@@ -0,0 +1,338 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp;
+use core::marker::PhantomData;
+use core::ops::{BitOr, Range, Shl};
+
+use num_traits::Num;
+
+use crate::backend::BLOCK_WORDS;
+use crate::{fits_in, from_bits};
+
+// Digits packed into one 64-bit word.
+const DIGITS: usize = 32;
+// Selects the low bit of every 2-bit digit.
+const LOW_BITS: u64 = 0x5555_5555_5555_5555;
+
+// A sequence of 2-bit digits with a rank directory for each digit.
+struct QuadRow {
+    words: Vec<u64>,
+    // `blocks[b][d]` counts the digits `d` before word `b * BLOCK_WORDS`. The
+    // last entry holds the totals, which covers `i == len` on a block boundary.
+    blocks: Vec<[u64; 4]>,
+}
+
+impl QuadRow {
+    fn new<I: Iterator<Item = u64>>(digits: I, len: usize) -> Self {
+        let mut words = vec![0u64; len.div_ceil(DIGITS)];
+        for (i, d) in digits.enumerate() {
+            words[i / DIGITS] |= d << (2 * (i % DIGITS));
+        }
+        let mut blocks = Vec::with_capacity(words.len() / BLOCK_WORDS + 1);
+        let mut counts = [0u64; 4];
+        for (i, &w) in words.iter().enumerate() {
+            if i % BLOCK_WORDS == 0 {
+                blocks.push(counts);
+            }
+            for (d, c) in counts.iter_mut().enumerate() {
+                *c += count_in_word(w, d as u64, DIGITS);
+            }
+        }
+        // Padding digits of the last word read as zeros.
+        counts[0] -= (words.len() * DIGITS - len) as u64;
+        blocks.push(counts);
+        QuadRow { words, blocks }
+    }
+
+    fn get(&self, i: u64) -> u64 {
+        let i = i as usize;
+        (self.words[i / DIGITS] >> (2 * (i % DIGITS))) & 3
+    }
+
+    // Counts the digits `d` before position `i`.
+    fn rank(&self, d: u64, i: u64) -> u64 {
+        let i = i as usize;
+        let w = i / DIGITS;
+        let b = w / BLOCK_WORDS;
+        let mut count = self.blocks[b][d as usize];
+        for j in b * BLOCK_WORDS..w {
+            count += count_in_word(self.words[j], d, DIGITS);
+        }
+        if i % DIGITS != 0 {
+            count += count_in_word(self.words[w], d, i % DIGITS);
+        }
+        count
+    }
+
+    // Returns the position of the (k+1)-th digit `d`, which must exist.
+    fn select(&self, d: u64, k: u64) -> u64 {
+        let b = self.blocks.partition_point(|c| c[d as usize] <= k) - 1;
+        let mut k = k - self.blocks[b][d as usize];
+        let mut w = b * BLOCK_WORDS;
+        loop {
+            let c = count_in_word(self.words[w], d, DIGITS);
+            if k < c {
+                break;
+            }
+            k -= c;
+            w += 1;
+        }
+        let word = self.words[w];
+        let mut j = 0;
+        loop {
+            if (word >> (2 * j)) & 3 == d {
+                if k == 0 {
+                    return (w * DIGITS + j) as u64;
+                }
+                k -= 1;
+            }
+            j += 1;
+        }
+    }
+}
+
+// Counts the digits `d` among the first `k` digits of `word`.
+fn count_in_word(word: u64, d: u64, k: usize) -> u64 {
+    let x = word ^ (LOW_BITS * d);
+    let mut m = !(x | (x >> 1)) & LOW_BITS;
+    if k < DIGITS {
+        m &= (1 << (2 * k)) - 1;
+    }
+    u64::from(m.count_ones())
+}
+
+/// A wavelet matrix that branches on two bits per level.
+///
+/// Each level splits its elements four ways by the next two bits of their
+/// values, so there are `size / 2` levels instead of `size`. This shortens the
+/// descent of `access`, `rank` and `select` at the cost of a wider rank per
+/// level. Queries behave like those of a `WaveletMatrix` built with
+/// `new_with_size(text, size)`.
+pub struct QuadWaveletMatrix<T> {
+    rows: Vec<QuadRow>,
+    // `offsets[r][d]` counts the elements of level `r` whose digit is less
+    // than `d`, i.e. where the elements with digit `d` start at level `r + 1`.
+    offsets: Vec<[u64; 4]>,
+    size: u64,
+    len: u64,
+    _t: PhantomData<T>,
+}
+
+impl<T> QuadWaveletMatrix<T>
+where
+    T: Into<u64> + Copy + Clone + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
+{
+    pub fn new<K: AsRef<[T]>>(text: K) -> Self {
+        Self::new_with_size(text, core::mem::size_of::<T>() as u64 * 8)
+    }
+
+    /// Builds a matrix with `size` bits per symbol.
+    ///
+    /// Panics if `size` is odd or greater than 64.
+    pub fn new_with_size<K: AsRef<[T]>>(text: K, size: u64) -> Self {
+        assert!(size <= 64, "size ({}) must be at most 64", size);
+        assert!(size % 2 == 0, "size ({}) must be even", size);
+        let mut cur: Vec<u64> = text.as_ref().iter().map(|&c| c.into()).collect();
+        let levels = (size / 2) as usize;
+        let mut rows = Vec::with_capacity(levels);
+        let mut offsets = Vec::with_capacity(levels);
+        for r in 0..levels as u64 {
+            let shift = size - 2 * r - 2;
+            let digit = |n: u64| (n >> shift) & 3;
+            rows.push(QuadRow::new(cur.iter().map(|&n| digit(n)), cur.len()));
+            let mut buckets: [Vec<u64>; 4] = Default::default();
+            for &n in &cur {
+                buckets[digit(n) as usize].push(n);
+            }
+            let mut offset = [0u64; 4];
+            for d in 1..4 {
+                offset[d] = offset[d - 1] + buckets[d - 1].len() as u64;
+            }
+            offsets.push(offset);
+            cur = buckets.concat();
+        }
+        QuadWaveletMatrix {
+            rows,
+            offsets,
+            size,
+            len: text.as_ref().len() as u64,
+            _t: PhantomData,
+        }
+    }
+
+    fn digit(&self, n: u64, r: usize) -> u64 {
+        (n >> (self.size - 2 * r as u64 - 2)) & 3
+    }
+
+    /// Returns the value at position `k`.
+    ///
+    /// `k` must be less than `len()`.
+    pub fn access(&self, k: u64) -> T {
+        let mut i = k;
+        let mut n = 0u64;
+        for (r, row) in self.rows.iter().enumerate() {
+            let d = row.get(i);
+            i = self.offsets[r][d as usize] + row.rank(d, i);
+            n |= d << (self.size - 2 * r as u64 - 2);
+        }
+        from_bits(n, self.size)
+    }
+
+    /// Counts the occurrences of `c` in `text[0..k)`.
+    pub fn rank(&self, c: T, k: u64) -> u64 {
+        let n = c.into();
+        if !fits_in(n, self.size) {
+            return 0;
+        }
+        let mut s = 0;
+        let mut e = cmp::min(k, self.len);
+        for (r, row) in self.rows.iter().enumerate() {
+            let d = self.digit(n, r);
+            let offset = self.offsets[r][d as usize];
+            s = offset + row.rank(d, s);
+            e = offset + row.rank(d, e);
+        }
+        e - s
+    }
+
+    /// Returns the position of the (k+1)-th occurrence of `c`.
+    ///
+    /// The occurrence must exist, i.e. `k < rank(c, len())`.
+    pub fn select(&self, c: T, k: u64) -> u64 {
+        let n = c.into();
+        let mut s = 0;
+        for (r, row) in self.rows.iter().enumerate() {
+            let d = self.digit(n, r);
+            s = self.offsets[r][d as usize] + row.rank(d, s);
+        }
+        let mut p = s + k;
+        for (r, row) in self.rows.iter().enumerate().rev() {
+            let d = self.digit(n, r);
+            p = row.select(d, p - self.offsets[r][d as usize]);
+        }
+        p
+    }
+
+    /// Returns the (k+1)-th smallest value in `text[range]`.
+    ///
+    /// Panics if `k` is not less than the length of the range.
+    pub fn quantile(&self, range: Range<u64>, k: u64) -> T {
+        let mut e = cmp::min(range.end, self.len);
+        let mut s = cmp::min(range.start, e);
+        assert!(
+            k < e - s,
+            "quantile: k ({}) must be less than the range length ({})",
+            k,
+            e - s
+        );
+        let mut k = k;
+        let mut n = 0u64;
+        for (r, row) in self.rows.iter().enumerate() {
+            for d in 0..4 {
+                let (ds, de) = (row.rank(d, s), row.rank(d, e));
+                if k < de - ds {
+                    let offset = self.offsets[r][d as usize];
+                    s = offset + ds;
+                    e = offset + de;
+                    n |= d << (self.size - 2 * r as u64 - 2);
+                    break;
+                }
+                k -= de - ds;
+            }
+        }
+        from_bits(n, self.size)
+    }
+
+    /// Counts the positions in `pos` whose value lies in `[val.start, val.end)`.
+    pub fn range_freq(&self, pos: Range<u64>, val: Range<T>) -> u64 {
+        if val.start.into() >= val.end.into() {
+            return 0;
+        }
+        self.count_less(val.end, pos.clone()) - self.count_less(val.start, pos)
+    }
+
+    // Counts the values less than `c` in `text[range]`.
+    fn count_less(&self, c: T, range: Range<u64>) -> u64 {
+        let n = c.into();
+        let mut e = cmp::min(range.end, self.len);
+        let mut s = cmp::min(range.start, e);
+        if !fits_in(n, self.size) {
+            return e - s;
+        }
+        let mut less = 0;
+        for (r, row) in self.rows.iter().enumerate() {
+            let d = self.digit(n, r);
+            for lower in 0..d {
+                less += row.rank(lower, e) - row.rank(lower, s);
+            }
+            let offset = self.offsets[r][d as usize];
+            s = offset + row.rank(d, s);
+            e = offset + row.rank(d, e);
+        }
+        less
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WaveletMatrix;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn same_answers_as_binary() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..3000).map(|_| rng.gen_range(0..1000)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 10);
+        let qm = QuadWaveletMatrix::new_with_size(&numbers, 10);
+        assert_eq!(qm.len(), wm.len());
+        for i in 0..wm.len() {
+            assert_eq!(qm.access(i), wm.access(i));
+        }
+        for _ in 0..500 {
+            let c = rng.gen_range(0..1100);
+            let k = rng.gen_range(0..=wm.len() + 5);
+            assert!(
+                qm.rank(c, k) == wm.rank(c, k),
+                "qm.rank({}, {}) == {}",
+                c,
+                k,
+                wm.rank(c, k)
+            );
+            let c = numbers[rng.gen_range(0..numbers.len())];
+            let k = rng.gen_range(0..wm.rank(c, wm.len()));
+            assert_eq!(qm.select(c, k), wm.select(c, k));
+            let i = rng.gen_range(0..wm.len());
+            let j = rng.gen_range(i + 1..=wm.len());
+            let k = rng.gen_range(0..j - i);
+            assert_eq!(qm.quantile(i..j, k), wm.quantile(i..j, k));
+            let lo = rng.gen_range(0..1100);
+            let hi = rng.gen_range(0..1100);
+            assert_eq!(qm.range_freq(i..j, lo..hi), wm.range_freq(i..j, lo..hi));
+        }
+        assert!(QuadWaveletMatrix::<u8>::new([]).is_empty());
+    }
+
+    #[test]
+    fn full_width() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7, 255, 128];
+        let qm = QuadWaveletMatrix::new(numbers);
+        let wm = WaveletMatrix::new(numbers);
+        for (i, &n) in numbers.iter().enumerate() {
+            assert_eq!(qm.access(i as u64), n);
+            assert_eq!(qm.rank(n, i as u64), wm.rank(n, i as u64));
+        }
+        assert_eq!(qm.select(1, 2), 10);
+        assert_eq!(qm.quantile(0..14, 13), 255);
+        assert_eq!(qm.range_freq(0..14, 4..255), 7);
+    }
+}
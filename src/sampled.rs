@@ -0,0 +1,179 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp;
+use core::ops::{BitOr, Shl};
+
+use fid::{BitVector, FID};
+use num_traits::Num;
+
+use crate::backend::HeapSize;
+use crate::WaveletMatrix;
+
+/// A row that keeps a rank sample every `rate` bits on top of the row `B`, as
+/// built by `WaveletMatrix::new_with_sampling`.
+///
+/// The bits are also kept as plain 64-bit words, so `rank` starts from the
+/// sample before the position and counts the remaining ones with popcounts.
+/// `select` is left to `B`.
+#[derive(Clone)]
+pub struct Sampled<B = BitVector> {
+    bits: B,
+    words: Vec<u64>,
+    // `samples[j]` counts the ones before bit `j * rate`.
+    samples: Vec<u64>,
+    rate: u64,
+}
+
+impl<B: FID> Sampled<B> {
+    fn new(bits: B, rate: u64) -> Self {
+        let len = bits.len();
+        let mut words = vec![0u64; len.div_ceil(64) as usize];
+        let mut samples = Vec::with_capacity((len / rate + 1) as usize);
+        let mut ones = 0;
+        for i in 0..len {
+            if i % rate == 0 {
+                samples.push(ones);
+            }
+            if bits.get(i) {
+                words[(i / 64) as usize] |= 1 << (i % 64);
+                ones += 1;
+            }
+        }
+        // Covers `rank1(len)` when `len` is a multiple of the rate.
+        if len % rate == 0 {
+            samples.push(ones);
+        }
+        Sampled {
+            bits,
+            words,
+            samples,
+            rate,
+        }
+    }
+
+    // Counts the ones in bits `[s, e)`.
+    fn ones_between(&self, s: u64, e: u64) -> u64 {
+        let mut ones = 0;
+        let mut p = s;
+        while p < e {
+            let lo = p % 64;
+            let n = cmp::min(64 - lo, e - p);
+            let mask = (u64::MAX >> (64 - n)) << lo;
+            ones += u64::from((self.words[(p / 64) as usize] & mask).count_ones());
+            p += n;
+        }
+        ones
+    }
+}
+
+impl<B: FID> FID for Sampled<B> {
+    fn len(&self) -> u64 {
+        self.bits.len()
+    }
+
+    fn rank1(&self, i: u64) -> u64 {
+        let j = i / self.rate;
+        self.samples[j as usize] + self.ones_between(j * self.rate, i)
+    }
+
+    fn select(&self, b: bool, r: u64) -> u64 {
+        self.bits.select(b, r)
+    }
+
+    fn select0(&self, r: u64) -> u64 {
+        self.bits.select0(r)
+    }
+
+    fn select1(&self, r: u64) -> u64 {
+        self.bits.select1(r)
+    }
+
+    fn get(&self, i: u64) -> bool {
+        (self.words[(i / 64) as usize] >> (i % 64)) & 1 > 0
+    }
+}
+
+impl<B: HeapSize> HeapSize for Sampled<B> {
+    fn heap_size(&self) -> usize {
+        self.bits.heap_size() + (self.words.capacity() + self.samples.capacity()) * 8
+    }
+}
+
+impl<T> WaveletMatrix<T>
+where
+    T: Into<u64> + Copy + Clone + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
+{
+    /// Builds a matrix with `size` bits per symbol whose rows also keep a rank
+    /// sample every `sample_rate` bits.
+    ///
+    /// `rank`, and the queries built on it, count from the nearest sample, so
+    /// a lower rate makes them faster at the cost of more samples. The answers
+    /// are the same as those of `new_with_size`. Panics if `sample_rate` is
+    /// zero or `size` is greater than 64.
+    pub fn new_with_sampling<K: AsRef<[T]>>(
+        text: K,
+        size: u64,
+        sample_rate: u64,
+    ) -> WaveletMatrix<T, Sampled> {
+        assert!(sample_rate > 0, "sample_rate must be positive");
+        let wm = Self::new_with_size(text, size);
+        WaveletMatrix {
+            rows: wm
+                .rows
+                .into_iter()
+                .map(|bv| Sampled::new(bv, sample_rate))
+                .collect(),
+            size: wm.size,
+            len: wm.len,
+            partitions: wm.partitions,
+            _t: core::marker::PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn same_answers_as_unsampled() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let numbers: Vec<u16> = (0..5000).map(|_| rng.gen_range(0..300)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 9);
+        for &rate in &[1, 7, 64, 100, 4096, 10000] {
+            let sampled = WaveletMatrix::new_with_sampling(&numbers, 9, rate);
+            assert_eq!(sampled.to_vec(), numbers);
+            for _ in 0..300 {
+                let c = rng.gen_range(0..300);
+                let k = rng.gen_range(0..=wm.len());
+                assert!(
+                    sampled.rank(c, k) == wm.rank(c, k),
+                    "rate {}: sampled.rank({}, {}) == {}",
+                    rate,
+                    c,
+                    k,
+                    wm.rank(c, k)
+                );
+            }
+            assert_eq!(
+                sampled.rank(numbers[0], wm.len()),
+                wm.rank(numbers[0], wm.len())
+            );
+            assert_eq!(sampled.select(numbers[42], 3), wm.select(numbers[42], 3));
+            assert_eq!(sampled.quantile(10..4000, 99), wm.quantile(10..4000, 99));
+        }
+        let empty = WaveletMatrix::<u16>::new_with_sampling([], 9, 64);
+        assert_eq!(empty.rank(3, 10), 0);
+    }
+
+    #[test]
+    fn sparse_sampling_is_smaller() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..5000).map(|_| rng.gen_range(0..300)).collect();
+        let dense = WaveletMatrix::new_with_sampling(&numbers, 9, 64);
+        let sparse = WaveletMatrix::new_with_sampling(&numbers, 9, 4096);
+        assert!(sparse.heap_size() < dense.heap_size());
+    }
+}
@@ -0,0 +1,126 @@
+use alloc::vec::Vec;
+use core::ops::{BitOr, Range, Shl};
+
+use num_traits::Num;
+
+use crate::WaveletMatrix;
+
+/// A signed integer type that can be stored in a `SignedWaveletMatrix`.
+///
+/// Values are mapped to the unsigned type of the same width by flipping the
+/// sign bit, which preserves their order.
+pub trait SignedSymbol: Copy {
+    type Unsigned: Into<u64>
+        + Copy
+        + Clone
+        + Num
+        + BitOr<Output = Self::Unsigned>
+        + Shl<u64, Output = Self::Unsigned>;
+
+    fn to_unsigned(self) -> Self::Unsigned;
+    fn from_unsigned(u: Self::Unsigned) -> Self;
+}
+
+macro_rules! impl_signed_symbol {
+    ($($s:ty => $u:ty),*) => {
+        $(
+            impl SignedSymbol for $s {
+                type Unsigned = $u;
+
+                fn to_unsigned(self) -> $u {
+                    (self as $u) ^ (1 << (<$u>::BITS - 1))
+                }
+
+                fn from_unsigned(u: $u) -> $s {
+                    (u ^ (1 << (<$u>::BITS - 1))) as $s
+                }
+            }
+        )*
+    };
+}
+
+impl_signed_symbol!(i8 => u8, i16 => u16, i32 => u32, i64 => u64);
+
+/// A wavelet matrix over signed integers.
+///
+/// Order-sensitive queries such as `quantile` and `range_freq` follow the
+/// signed order of the values.
+pub struct SignedWaveletMatrix<S: SignedSymbol> {
+    inner: WaveletMatrix<S::Unsigned>,
+}
+
+impl<S: SignedSymbol> SignedWaveletMatrix<S> {
+    pub fn new<K: AsRef<[S]>>(text: K) -> Self {
+        let text: Vec<S::Unsigned> = text.as_ref().iter().map(|&c| c.to_unsigned()).collect();
+        SignedWaveletMatrix {
+            inner: WaveletMatrix::new(text),
+        }
+    }
+
+    pub fn access(&self, k: u64) -> S {
+        S::from_unsigned(self.inner.access(k))
+    }
+
+    pub fn rank(&self, c: S, k: u64) -> u64 {
+        self.inner.rank(c.to_unsigned(), k)
+    }
+
+    pub fn select(&self, c: S, k: u64) -> u64 {
+        self.inner.select(c.to_unsigned(), k)
+    }
+
+    /// Returns the (k+1)-th smallest value in `text[range]`.
+    pub fn quantile(&self, range: Range<u64>, k: u64) -> S {
+        S::from_unsigned(self.inner.quantile(range, k))
+    }
+
+    /// Counts the positions in `pos` whose value lies in `[val.start, val.end)`.
+    pub fn range_freq(&self, pos: Range<u64>, val: Range<S>) -> u64 {
+        self.inner
+            .range_freq(pos, val.start.to_unsigned()..val.end.to_unsigned())
+    }
+
+    pub fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_small() {
+        let numbers = &[-4i16, 7, -32768, 5, 0, -1, 32767, 0, -1, 4, 1, -4];
+        let wm = SignedWaveletMatrix::new(numbers);
+        assert_eq!(wm.len(), numbers.len() as u64);
+        for (i, &n) in numbers.iter().enumerate() {
+            assert_eq!(wm.access(i as u64), n);
+        }
+        for &c in numbers {
+            let mut r = 0;
+            for (k, &n) in numbers.iter().enumerate() {
+                assert_eq!(wm.rank(c, k as u64), r);
+                if n == c {
+                    assert_eq!(wm.select(c, r), k as u64);
+                    r += 1;
+                }
+            }
+        }
+        for i in 0..numbers.len() {
+            for j in (i + 1)..=numbers.len() {
+                let mut sorted = numbers[i..j].to_vec();
+                sorted.sort();
+                for (k, &n) in sorted.iter().enumerate() {
+                    assert_eq!(wm.quantile(i as u64..j as u64, k as u64), n);
+                }
+            }
+        }
+        let f = numbers.iter().filter(|&&n| (-4..5).contains(&n)).count() as u64;
+        assert_eq!(wm.range_freq(0..12, -4..5), f);
+    }
+}
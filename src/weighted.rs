@@ -0,0 +1,177 @@
+use alloc::vec::Vec;
+use core::cmp;
+use core::ops::{BitOr, Range, Shl, Sub};
+
+use fid::FID;
+use num_traits::{Num, Zero};
+
+use crate::{fits_in, WaveletMatrix};
+
+/// A wavelet matrix whose positions carry weights of type `W`.
+///
+/// Each row keeps the prefix sums of the weights in the order of that row, so
+/// `range_sum` touches one entry per row instead of scanning the range.
+pub struct WeightedWaveletMatrix<T, W> {
+    inner: WaveletMatrix<T>,
+    // `sums[r][i]` is the total weight of the first `i` elements in the order
+    // of row `r`. The last entry follows the order after the final partition.
+    sums: Vec<Vec<W>>,
+}
+
+impl<T, W> WeightedWaveletMatrix<T, W>
+where
+    T: Into<u64> + Copy + Clone + Num + BitOr<T, Output = T> + Shl<u64, Output = T>,
+    W: Copy + Zero + Sub<Output = W>,
+{
+    /// Builds a matrix over `text` where `text[i]` has weight `weights[i]`.
+    ///
+    /// Panics if `text` and `weights` have different lengths.
+    pub fn new<K: AsRef<[T]>, V: AsRef<[W]>>(text: K, weights: V) -> Self {
+        Self::new_with_size(text, weights, core::mem::size_of::<T>() as u64 * 8)
+    }
+
+    /// Same as `new`, but uses `size` bits per value.
+    pub fn new_with_size<K: AsRef<[T]>, V: AsRef<[W]>>(text: K, weights: V, size: u64) -> Self {
+        let text = text.as_ref();
+        let weights = weights.as_ref();
+        assert_eq!(
+            text.len(),
+            weights.len(),
+            "text and weights must have the same length"
+        );
+        let inner = WaveletMatrix::new_with_size(text, size);
+        let mut cur: Vec<(u64, W)> = text
+            .iter()
+            .map(|&c| c.into())
+            .zip(weights.iter().cloned())
+            .collect();
+        let mut sums = Vec::with_capacity(size as usize + 1);
+        for r in 0..size {
+            sums.push(prefix_sums(&cur));
+            let shift = size - r - 1;
+            let (mut zeros, ones): (Vec<_>, Vec<_>) =
+                cur.into_iter().partition(|&(n, _)| (n >> shift) & 1 == 0);
+            zeros.extend(ones);
+            cur = zeros;
+        }
+        sums.push(prefix_sums(&cur));
+        WeightedWaveletMatrix { inner, sums }
+    }
+
+    /// Returns the total weight of the positions in `pos` whose value lies in
+    /// `[val.start, val.end)`.
+    pub fn range_sum(&self, pos: Range<u64>, val: Range<T>) -> W {
+        if val.start.into() >= val.end.into() {
+            return W::zero();
+        }
+        self.sum_less(val.end, pos.clone()) - self.sum_less(val.start, pos)
+    }
+
+    // Sums the weights of the values less than `c` in `text[range]`.
+    fn sum_less(&self, c: T, range: Range<u64>) -> W {
+        let wm = &self.inner;
+        let n = c.into();
+        let mut e = cmp::min(range.end, wm.len);
+        let mut s = cmp::min(range.start, e);
+        if !fits_in(n, wm.size) {
+            return self.sums[0][e as usize] - self.sums[0][s as usize];
+        }
+        let mut sum = W::zero();
+        for (r, bv) in wm.rows.iter().enumerate() {
+            let b = (n >> (wm.size - (r as u64) - 1)) & 1 > 0;
+            let zs = bv.rank0(s);
+            let ze = bv.rank0(e);
+            if b {
+                let next = &self.sums[r + 1];
+                sum = sum + (next[ze as usize] - next[zs as usize]);
+                let z = wm.partitions[r];
+                s = z + bv.rank1(s);
+                e = z + bv.rank1(e);
+            } else {
+                s = zs;
+                e = ze;
+            }
+        }
+        sum
+    }
+
+    /// Returns the underlying matrix.
+    pub fn matrix(&self) -> &WaveletMatrix<T> {
+        &self.inner
+    }
+
+    pub fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+}
+
+fn prefix_sums<W: Copy + Zero>(elems: &[(u64, W)]) -> Vec<W> {
+    let mut sums = Vec::with_capacity(elems.len() + 1);
+    let mut acc = W::zero();
+    sums.push(acc);
+    for &(_, w) in elems {
+        acc = acc + w;
+        sums.push(acc);
+    }
+    sums
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn range_sum_small() {
+        let numbers = &[4u8, 7, 6, 5, 3, 2, 1, 0, 1, 4, 1, 7];
+        let weights = &[1u64, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let wm = WeightedWaveletMatrix::new_with_size(numbers, weights, 3);
+        assert_eq!(wm.len(), 12);
+        for i in 0..numbers.len() {
+            for j in i..=numbers.len() {
+                for lo in 0..8u8 {
+                    for hi in lo..=8u8 {
+                        let naive: u64 = (i..j)
+                            .filter(|&p| lo <= numbers[p] && numbers[p] < hi)
+                            .map(|p| weights[p])
+                            .sum();
+                        assert!(
+                            wm.range_sum(i as u64..j as u64, lo..hi) == naive,
+                            "wm.range_sum({}..{}, {}..{}) == {}",
+                            i,
+                            j,
+                            lo,
+                            hi,
+                            naive
+                        );
+                    }
+                }
+            }
+        }
+        assert_eq!(wm.range_sum(0..12, 5..5), 0);
+    }
+
+    #[test]
+    fn range_sum_random() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..300).map(|_| rng.gen()).collect();
+        let weights: Vec<i64> = (0..300).map(|_| rng.gen_range(-1000..1000)).collect();
+        let wm = WeightedWaveletMatrix::new(&numbers, &weights);
+        for _ in 0..300 {
+            let i = rng.gen_range(0..=numbers.len());
+            let j = rng.gen_range(i..=numbers.len() + 10);
+            let lo: u16 = rng.gen();
+            let hi: u16 = rng.gen_range(lo..=u16::MAX);
+            let naive: i64 = (i..cmp::min(j, numbers.len()))
+                .filter(|&p| lo <= numbers[p] && numbers[p] < hi)
+                .map(|p| weights[p])
+                .sum();
+            assert_eq!(wm.range_sum(i as u64..j as u64, lo..hi), naive);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use alloc::vec::Vec;
+
+use fid::FID;
+
+use crate::backend::{rank1_in_block, FromOptions, HeapSize, PushBit, BLOCK_WORDS};
+
+/// A bit row stored as plain 64-bit words with a rank directory.
+///
+/// The directory keeps the number of ones before every block of
+/// `block_words` words, so a smaller block makes `rank` scan fewer words at
+/// the cost of a larger directory.
+#[derive(Clone)]
+#[cfg_attr(
+    feature = "rkyv",
+    derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize)
+)]
+pub struct WordBits {
+    pub(crate) words: Vec<u64>,
+    // `blocks[b]` counts the ones before word `b * block_words`.
+    pub(crate) blocks: Vec<u64>,
+    pub(crate) block_words: u64,
+    pub(crate) len: u64,
+    pub(crate) ones: u64,
+}
+
+impl WordBits {
+    /// Creates an empty row with one directory entry per `block_words` words.
+    ///
+    /// Panics if `block_words` is zero.
+    pub fn with_block_words(block_words: usize) -> Self {
+        assert!(block_words > 0, "block_words must be positive");
+        WordBits {
+            words: Vec::new(),
+            blocks: Vec::new(),
+            block_words: block_words as u64,
+            len: 0,
+            ones: 0,
+        }
+    }
+}
+
+impl Default for WordBits {
+    fn default() -> Self {
+        Self::with_block_words(BLOCK_WORDS)
+    }
+}
+
+impl PushBit for WordBits {
+    fn push_bit(&mut self, b: bool) {
+        let i = self.len as usize;
+        if i % 64 == 0 {
+            if (i / 64) % self.block_words as usize == 0 {
+                self.blocks.push(self.ones);
+            }
+            self.words.push(0);
+        }
+        if b {
+            self.words[i / 64] |= 1 << (i % 64);
+            self.ones += 1;
+        }
+        self.len += 1;
+    }
+}
+
+impl FID for WordBits {
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    fn rank1(&self, i: u64) -> u64 {
+        // A block is only started by its first bit, so `i == len` on a block
+        // boundary falls back to the total.
+        let block_words = self.block_words as usize;
+        let block = i as usize / 64 / block_words;
+        let ones = self.blocks.get(block).copied().unwrap_or(self.ones);
+        rank1_in_block(block_words, ones, |j| self.words[j], i)
+    }
+
+    fn get(&self, i: u64) -> bool {
+        (self.words[i as usize / 64] >> (i % 64)) & 1 > 0
+    }
+}
+
+impl FromOptions for WordBits {
+    fn from_options(opts: BuildOptions) -> Self {
+        Self::with_block_words(opts.block_words)
+    }
+}
+
+impl HeapSize for WordBits {
+    fn heap_size(&self) -> usize {
+        (self.words.capacity() + self.blocks.capacity()) * 8
+    }
+}
+
+/// Options for `WaveletMatrix::new_with_size_and_opts`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildOptions {
+    /// Number of 64-bit words per entry of the rank directory of each
+    /// `WordBits` row. Must be positive.
+    pub block_words: usize,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            block_words: BLOCK_WORDS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WaveletMatrix;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn same_answers_as_bit_vector() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..3000).map(|_| rng.gen_range(0..300)).collect();
+        let wm = WaveletMatrix::new_with_size(&numbers, 9);
+        let words = WaveletMatrix::<u16, WordBits>::new_with_backend(&numbers, 9);
+        for _ in 0..500 {
+            let c = rng.gen_range(0..300);
+            let k = rng.gen_range(0..=wm.len());
+            assert_eq!(words.rank(c, k), wm.rank(c, k));
+        }
+        assert_eq!(words.to_vec(), numbers);
+    }
+
+    #[test]
+    fn block_words_options() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let numbers: Vec<u16> = (0..5000).map(|_| rng.gen_range(0..300)).collect();
+        let build =
+            |opts| WaveletMatrix::<u16, WordBits>::new_with_size_and_opts(&numbers, 9, opts);
+        let dense = build(BuildOptions { block_words: 1 });
+        let sparse = build(BuildOptions { block_words: 32 });
+        let default = build(BuildOptions::default());
+        assert!(dense == sparse && sparse == default);
+        for _ in 0..500 {
+            let c = rng.gen_range(0..300);
+            let k = rng.gen_range(0..=dense.len());
+            assert_eq!(dense.rank(c, k), sparse.rank(c, k));
+            assert_eq!(dense.rank(c, k), default.rank(c, k));
+        }
+        assert_eq!(dense.quantile(100..900, 17), sparse.quantile(100..900, 17));
+        assert!(dense.heap_size() > default.heap_size());
+        assert!(default.heap_size() > sparse.heap_size());
+        let backend = WaveletMatrix::<u16, WordBits>::new_with_backend(&numbers, 9);
+        assert_eq!(default.heap_size(), backend.heap_size());
+    }
+}